@@ -15,7 +15,7 @@ use std::{
     collections::HashMap,
     env,
     thread,
-    time::Duration
+    time::{Duration, SystemTime, UNIX_EPOCH}
 };
 use reqwest::{
     header::{Headers, UserAgent, ContentType},
@@ -23,8 +23,8 @@ use reqwest::{
     StatusCode
 };
 use queue::{
-    queue_info::{PushStatus, PushInfo, QueueSubscriber, QueueType},
-    message::{PushMessage, Message},
+    queue_info::{PushStatus, PushInfo, QueueInfo, QueueSubscriber, QueueType},
+    message::{is_valid_attribute, HistoryEvent, PushMessage, Message, HISTORY_TTL, FAILED_SUBSCRIBER_ATTRIBUTE, FAILED_STATUS_ATTRIBUTE},
     queue::Queue
 };
 use base64::encode;
@@ -50,16 +50,25 @@ fn construct_headers(headers: HashMap<String, String>, skip_content_type: bool)
     result
 }
 
-fn send_message_to_error_queue(message: Message, error_queue_name: String) {
+/// API path of a queue in the project of `queue_info`.
+fn queue_path(queue_info: &QueueInfo, queue_name: &String) -> String {
     let web_api_url = env::var("WEB_API_URL").expect("$WEB_API_URL is provided");
-    let path = format!("{}/queues/{}/messages", web_api_url, error_queue_name);
+    let project_id = queue_info.project_id.clone().unwrap_or(String::new());
+
+    format!("{}/3/projects/{}/queues/{}", web_api_url, project_id, queue_name)
+}
+
+fn send_message_to_error_queue(message: Message, queue_info: &QueueInfo, error_queue_name: String) {
+    let path = format!("{}/messages", queue_path(queue_info, &error_queue_name));
     info!("PATH: {:?}", path);
 
     let mut messages: Vec<Message> = Vec::new();
     messages.push(message);
 
     let reqwest_client = reqwest::Client::new();
-    let body = json!(messages);
+    let body = json!({
+        "messages": messages
+    });
     let res = reqwest_client
         .post(path.as_str())
         .headers(construct_headers(HashMap::new(), false))
@@ -74,11 +83,24 @@ fn post_message_to_subscriber(message: Message, subscriber: QueueSubscriber) ->
     info!("Subscriber: {:#?}", subscriber.url);
     info!("MSG: {:#?}", message.body);
     let reqwest_client = reqwest::Client::new();
-    let content = message.body.clone();
-    let headers = subscriber.headers.unwrap();
+    // Subscribers get the original payload, binary bodies are decoded from base64.
+    let content = message.payload().unwrap_or(message.body.clone().into_bytes());
+    let mut headers = subscriber.headers.unwrap();
+    let content_type = message.content_type.clone();
+    if let Some(ref content_type) = content_type {
+        headers.insert(String::from("Content-Type"), content_type.clone());
+    }
+    if let Some(attributes) = message.attributes {
+        // Attributes are validated on push, this keeps ones stored before that out of the headers.
+        for (name, value) in attributes {
+            if is_valid_attribute(&name, &value) {
+                headers.insert(format!("X-Attr-{}", name), value);
+            }
+        }
+    }
     let url = subscriber.url.unwrap();
     reqwest_client.post(url.as_str())
-        .headers(construct_headers(headers, false))
+        .headers(construct_headers(headers, content_type.is_some()))
         .body(content)
         .send().unwrap()
 }
@@ -92,16 +114,13 @@ fn update_push_status(push_message: PushMessage, subscriber: QueueSubscriber, st
     let client = prepare_client();
     let connection = client.get_connection().unwrap();
     let msg_key = {
-        let queue_name = {
-            let queue_info = push_message.queue_info.clone();
-            queue_info.name.unwrap()
-        };
+        let queue_id = push_message.queue_info.queue_id();
         let msg = push_message.msg.clone();
-        let queue_key = Queue::get_queue_key(&queue_name);
+        let queue_key = Queue::get_queue_key(&queue_id);
         let mut msg_key = String::new();
         msg_key.push_str(&queue_key);
         msg_key.push_str(":msg:");
-        msg_key.push_str(&msg.source_msg_id.unwrap());
+        msg_key.push_str(&msg.id.unwrap());
         msg_key.push_str(":delivery:");
         let delivery_id = encode(&subscriber.url.clone().unwrap());
         msg_key.push_str(delivery_id.as_str());
@@ -124,12 +143,35 @@ fn update_push_status(push_message: PushMessage, subscriber: QueueSubscriber, st
 
     let () = connection.hset(msg_key, "push_status", serde_json::to_string(&push_status).unwrap())?;
 
+    if status.is_success() {
+        log_delivery(&connection, &push_message, Some(push_status.subscriber_name))?;
+    }
+
     Ok(true)
 }
 
-fn call_delete_message(queue_name: String, message_id: String) {
-    let web_api_url = env::var("WEB_API_URL").expect("$WEB_API_URL is provided");
-    let path = format!("{}/queues/{}/messages/{}", web_api_url, queue_name, message_id);
+fn log_delivery(connection: &redis::Connection, push_message: &PushMessage, subscriber_name: Option<String>) -> Result<(), Error> {
+    let queue_id = push_message.queue_info.queue_id();
+    let history: Option<String> = connection.hget(Queue::get_queue_key(&queue_id), "history")?;
+    if history != Some(String::from("1")) {
+        return Ok(());
+    }
+
+    let event = HistoryEvent {
+        event: String::from("delivered"),
+        at: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64,
+        reservation_id: None,
+        subscriber_name
+    };
+    let history_key = Queue::get_message_history_key(&queue_id, &push_message.msg.id.clone().unwrap());
+    let () = connection.rpush(&history_key, serde_json::to_string(&event)?)?;
+    let () = connection.expire(&history_key, HISTORY_TTL)?;
+
+    Ok(())
+}
+
+fn call_delete_message(queue_info: &QueueInfo, message_id: String) {
+    let path = format!("{}/messages/{}", queue_path(queue_info, queue_info.name.as_ref().unwrap()), message_id);
     info!("PATH: {:?}", path);
 
     let reqwest_client = reqwest::Client::new();
@@ -150,7 +192,7 @@ fn main() -> Result<(), Error> {
     let client = prepare_client();
     let mut connection = client.get_connection()?;
     let mut pubsub = connection.as_pubsub();
-    let () = pubsub.psubscribe("queue:*:msg:channel")?;
+    let () = pubsub.psubscribe("project:*:queue:*:msg:channel")?;
 
     loop {
         let msg = pubsub.get_message()?;
@@ -186,6 +228,7 @@ fn main() -> Result<(), Error> {
 
                 let is_unicast_mode = |queue_type: Option<QueueType>| queue_type.unwrap() == QueueType::Unicast;
                 let delay = Duration::from_secs(retry.retry_delay.into());
+                let mut last_failure: Option<(String, StatusCode)> = None;
                 for i in 1..=retry.retry_count {
                     info!("Retry num: {:#?}", i);
                     let mut break_retry = false;
@@ -204,19 +247,27 @@ fn main() -> Result<(), Error> {
                         } else {
                             info!("Something else happened. Status: {:?}", res.status());
                             break_retry = false;
+                            last_failure = Some((subscriber.name.clone(), res.status()));
                         }
                     }
 
                     if break_retry == true {
                         info!("No retry is required");
-                        call_delete_message(pm.queue_info.name.clone().unwrap(), msg.id.clone().unwrap());
+                        call_delete_message(&pm.queue_info, msg.id.clone().unwrap());
                         break;
                     }
                     if retry.retry_count == i {
                         info!("No delivery. Moved to error_queue.");
                         if !error_queue_name.is_empty() {
-                            let message = Message::with_body(payload.as_str());
-                            send_message_to_error_queue(message, error_queue_name);
+                            let mut message = Message::with_body(payload.as_str());
+                            // Redrive uses these to pick messages by the subscriber and status that failed.
+                            if let Some((subscriber_name, status)) = last_failure.take() {
+                                let mut attributes = HashMap::new();
+                                attributes.insert(FAILED_SUBSCRIBER_ATTRIBUTE.to_string(), subscriber_name);
+                                attributes.insert(FAILED_STATUS_ATTRIBUTE.to_string(), status.as_u16().to_string());
+                                message.attributes = Some(attributes);
+                            }
+                            send_message_to_error_queue(message, &pm.queue_info, error_queue_name);
                         }
 
                         break;
@@ -0,0 +1,37 @@
+use std::io::{Read, Result, Write};
+use flate2::{
+    Compression,
+    read::GzDecoder,
+    write::GzEncoder
+};
+
+/// Compression of stored message bodies.
+pub const GZIP_COMPRESSION: &str = "gzip";
+
+pub fn compress(payload: &[u8]) -> Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(payload)?;
+
+    encoder.finish()
+}
+
+pub fn decompress(compressed: &[u8]) -> Result<Vec<u8>> {
+    let mut payload = Vec::new();
+    GzDecoder::new(compressed).read_to_end(&mut payload)?;
+
+    Ok(payload)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decompress_restores_compressed_payload() {
+        let payload = "{\"key\": \"value\"}".repeat(1000).into_bytes();
+        let compressed = compress(&payload).unwrap();
+
+        assert!(compressed.len() < payload.len());
+        assert_eq!(decompress(&compressed).unwrap(), payload);
+    }
+}
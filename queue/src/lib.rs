@@ -2,7 +2,10 @@ extern crate serde_json;
 #[macro_use]
 extern crate serde_derive;
 extern crate serde;
+extern crate base64;
+extern crate flate2;
 
 pub mod queue_info;
 pub mod message;
 pub mod queue;
+pub mod compression;
@@ -1,16 +1,37 @@
-use std::fmt;
+use std::{
+    collections::HashMap,
+    fmt
+};
+use serde::{Deserialize, Deserializer};
+use serde_json;
+use base64::{decode, encode};
 use queue_info::QueueInfo;
 
+/// Encoding of message bodies that carry binary payloads as base64 text.
+pub const BASE64_ENCODING: &str = "base64";
+/// Highest priority a message can have. Higher priorities are reserved first.
+pub const MAXIMUM_PRIORITY: u8 = 9;
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Message {
+    #[serde(default, skip_serializing_if = "Option::is_none", deserialize_with = "deserialize_attributes")]
+    pub attributes: Option<HashMap<String, String>>,
     pub body: String,
+    #[serde(skip_serializing_if = "Option::is_none")] pub consumer_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")] pub content_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")] pub dedup_id: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")] pub delay: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")] pub deliver_at: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")] pub encoding: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")] pub group_id: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")] pub id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")] pub priority: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")] pub reserved_at: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")] pub reserved_count: Option<u32>,
     #[serde(skip_serializing_if = "Option::is_none")] pub reservation_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")] pub reserved_until: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")] pub source_msg_id: Option<String>,
-    #[serde(skip_serializing)]
-    pub state: Option<MessageState>
+    #[serde(skip_serializing_if = "Option::is_none")] pub state: Option<MessageState>
 }
 
 impl Message {
@@ -22,6 +43,69 @@ impl Message {
     }
 }
 
+/// Attributes come as a map in API requests, but as a JSON string from the message hash.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Attributes {
+    Map(HashMap<String, String>),
+    Json(String)
+}
+
+fn deserialize_attributes<'de, D>(deserializer: D) -> Result<Option<HashMap<String, String>>, D::Error>
+    where D: Deserializer<'de>
+{
+    match Option::<Attributes>::deserialize(deserializer)? {
+        Some(Attributes::Map(attributes)) => Ok(Some(attributes)),
+        Some(Attributes::Json(json)) => serde_json::from_str(&json)
+            .map(Some)
+            .map_err(::serde::de::Error::custom),
+        None => Ok(None)
+    }
+}
+
+/// Selects messages for bulk operations. Messages match when every given criterion matches.
+/// Without a `state`, messages that are not reserved are selected.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct MessageFilter {
+    #[serde(skip_serializing_if = "Option::is_none")] pub state: Option<MessageState>,
+    #[serde(skip_serializing_if = "Option::is_none")] pub group_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")] pub attributes: Option<HashMap<String, String>>
+}
+
+impl MessageFilter {
+    pub fn matches(&self, message: &Message) -> bool {
+        let state_matches = match self.state {
+            Some(ref state) => message.state.as_ref() == Some(state),
+            None => message.state != Some(MessageState::Reserved)
+        };
+        let group_matches = self.group_id.is_none() || self.group_id == message.group_id;
+        let attributes_match = match self.attributes {
+            Some(ref attributes) => attributes.iter().all(|(name, value)| {
+                message.attributes.as_ref().and_then(|a| a.get(name)) == Some(value)
+            }),
+            None => true
+        };
+
+        state_matches && group_matches && attributes_match
+    }
+}
+
+/// Attributes the pusher sets on messages it moves to the error queue.
+pub const FAILED_SUBSCRIBER_ATTRIBUTE: &str = "failed_subscriber";
+pub const FAILED_STATUS_ATTRIBUTE: &str = "failed_status";
+
+/// Seconds a message history is kept after its last event.
+pub const HISTORY_TTL: usize = 604800;
+
+/// A state transition of a message, kept for queues with history enabled.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HistoryEvent {
+    pub event: String,
+    pub at: i64,
+    #[serde(skip_serializing_if = "Option::is_none")] pub reservation_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")] pub subscriber_name: Option<String>
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct PushMessage {
     pub queue_info: QueueInfo,
@@ -32,14 +116,16 @@ pub struct PushMessage {
 #[serde(rename_all = "lowercase")]
 pub enum MessageState {
     Reserved,
-    Unreserved
+    Unreserved,
+    Delayed
 }
 
 impl fmt::Display for MessageState {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
             MessageState::Unreserved => write!(f, "unreserved"),
-            MessageState::Reserved => write!(f, "reserved")
+            MessageState::Reserved => write!(f, "reserved"),
+            MessageState::Delayed => write!(f, "delayed")
         }
     }
 }
@@ -47,11 +133,21 @@ impl fmt::Display for MessageState {
 impl Message {
     pub fn new(body: &str, delay: u32) -> Message {
         Message {
+            attributes: None,
             body: String::from(body),
+            consumer_id: None,
+            content_type: None,
+            dedup_id: None,
             delay: Some(delay),
+            deliver_at: None,
+            encoding: None,
+            group_id: None,
             id: None,
+            priority: None,
+            reserved_at: None,
             reserved_count: None,
             reservation_id: None,
+            reserved_until: None,
             source_msg_id: None,
             state: Some(MessageState::Unreserved)
         }
@@ -59,15 +155,68 @@ impl Message {
 
     pub fn with_body(body: &str) -> Message {
         Message {
+            attributes: None,
             body: String::from(body),
+            consumer_id: None,
+            content_type: None,
+            dedup_id: None,
             delay: None,
+            deliver_at: None,
+            encoding: None,
+            group_id: None,
             id: None,
+            priority: None,
+            reserved_at: None,
             reserved_count: None,
             reservation_id: None,
+            reserved_until: None,
             source_msg_id: None,
             state: Some(MessageState::Unreserved)
         }
     }
+
+    /// Creates a message for a raw payload. Payloads that are not UTF-8 are kept base64 encoded.
+    pub fn with_payload(payload: &[u8], content_type: Option<String>) -> Message {
+        let mut message = match String::from_utf8(payload.to_vec()) {
+            Ok(body) => Message::with_body(&body),
+            Err(_) => {
+                let mut message = Message::with_body(&encode(payload));
+                message.encoding = Some(String::from(BASE64_ENCODING));
+                message
+            }
+        };
+        message.content_type = content_type;
+
+        message
+    }
+
+    /// Returns the raw payload, or `None` when the body is not valid for its encoding.
+    pub fn payload(&self) -> Option<Vec<u8>> {
+        match self.encoding.as_ref().map(|encoding| encoding.as_str()) {
+            None => Some(self.body.clone().into_bytes()),
+            Some(BASE64_ENCODING) => decode(&self.body).ok(),
+            Some(_) => None
+        }
+    }
+
+    pub fn is_binary(&self) -> bool {
+        self.encoding.as_ref().map(|encoding| encoding.as_str()) == Some(BASE64_ENCODING)
+    }
+
+    pub fn has_valid_attributes(&self) -> bool {
+        match self.attributes {
+            Some(ref attributes) => attributes.iter().all(|(name, value)| is_valid_attribute(name, value)),
+            None => true
+        }
+    }
+}
+
+/// Attributes are sent to subscribers as `X-Attr-<name>` headers, so names must be
+/// RFC 7230 tokens and values must not contain control characters such as CR or LF.
+pub fn is_valid_attribute(name: &str, value: &str) -> bool {
+    let is_token_char = |c: char| c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c);
+
+    !name.is_empty() && name.chars().all(is_token_char) && !value.chars().any(|c| c.is_control())
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -88,3 +237,70 @@ impl ReservationConfig {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn attributes_are_read_from_map_and_json_string() {
+        let from_map: Message = serde_json::from_str(r#"{"body":"b","attributes":{"tenant":"acme"}}"#).unwrap();
+        let from_json: Message = serde_json::from_str(r#"{"body":"b","attributes":"{\"tenant\":\"acme\"}"}"#).unwrap();
+        let without: Message = serde_json::from_str(r#"{"body":"b"}"#).unwrap();
+
+        assert_eq!(from_map.attributes.unwrap()["tenant"], "acme");
+        assert_eq!(from_json.attributes.unwrap()["tenant"], "acme");
+        assert!(without.attributes.is_none());
+    }
+
+    #[test]
+    fn attributes_that_would_break_headers_are_rejected() {
+        assert!(is_valid_attribute("tenant-id", "acme corp"));
+        assert!(is_valid_attribute("x_trace.id", ""));
+        assert!(!is_valid_attribute("", "acme"));
+        assert!(!is_valid_attribute("tenant id", "acme"));
+        assert!(!is_valid_attribute("tenant:id", "acme"));
+        assert!(!is_valid_attribute("tenant", "acme\r\nX-Injected: 1"));
+        assert!(!is_valid_attribute("tenant", "acme\u{0}"));
+
+        let mut message = Message::with_body("b");
+        assert!(message.has_valid_attributes());
+        message.attributes = Some([(String::from("tenant"), String::from("a\nb"))].iter().cloned().collect());
+        assert!(!message.has_valid_attributes());
+    }
+
+    #[test]
+    fn payload_round_trips_through_base64() {
+        let binary = Message::with_payload(&[0, 159, 146, 150], Some(String::from("application/x-protobuf")));
+        let text = Message::with_payload(b"{\"a\": 1}", None);
+
+        assert!(binary.is_binary());
+        assert_eq!(binary.payload().unwrap(), vec![0, 159, 146, 150]);
+        assert!(!text.is_binary());
+        assert_eq!(text.body, "{\"a\": 1}");
+
+        let mut invalid = Message::with_body("not base64!");
+        invalid.encoding = Some(String::from(BASE64_ENCODING));
+        assert!(invalid.payload().is_none());
+    }
+
+    #[test]
+    fn filter_matches_state_group_and_attributes() {
+        let mut message = Message::with_body("b");
+        message.group_id = Some(String::from("customer-1"));
+        message.attributes = Some([(String::from("tenant"), String::from("acme"))].iter().cloned().collect());
+
+        let mut filter = MessageFilter::default();
+        assert!(filter.matches(&message));
+
+        filter.group_id = Some(String::from("customer-1"));
+        filter.attributes = Some([(String::from("tenant"), String::from("acme"))].iter().cloned().collect());
+        assert!(filter.matches(&message));
+
+        filter.attributes = Some([(String::from("tenant"), String::from("other"))].iter().cloned().collect());
+        assert!(!filter.matches(&message));
+
+        message.state = Some(MessageState::Reserved);
+        assert!(!MessageFilter::default().matches(&message));
+    }
+}
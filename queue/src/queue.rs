@@ -1,17 +1,40 @@
+use queue_info::QueueType;
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Queue {
     pub name: Option<String>,
     pub value: Option<String>,
     pub size: Option<usize>,
-    pub total_messages: Option<usize>
+    pub total_messages: Option<usize>,
+    pub expired_messages: Option<usize>,
+    pub compressed_bytes: Option<usize>,
+    pub uncompressed_bytes: Option<usize>
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct QueueLite {
-    pub name: String
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")] pub size: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")] pub total_messages: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "type")]
+    pub queue_type: Option<QueueType>
+}
+
+impl QueueLite {
+    pub fn new(name: String) -> QueueLite {
+        QueueLite {
+            name,
+            size: None,
+            total_messages: None,
+            queue_type: None
+        }
+    }
 }
 
 pub const DEFAULT_QUEUE_KEY: &'static str = "queue:";
+pub const DEFAULT_PROJECT_KEY: &'static str = "project:";
+pub const PROJECT_QUEUE_KEY: &'static str = ":queue:";
 
 impl Queue {
     pub fn new() -> Queue {
@@ -19,15 +42,110 @@ impl Queue {
             name: None,
             value: None,
             size: None,
-            total_messages: None
+            total_messages: None,
+            expired_messages: None,
+            compressed_bytes: None,
+            uncompressed_bytes: None
         }
     }
 
+    /// Queues are namespaced by project, their id is `<project_id>:<name>`.
+    pub fn get_queue_id(project_id: &String, queue_name: &String) -> String {
+        let mut id = String::new();
+        id.push_str(project_id);
+        id.push_str(":");
+        id.push_str(queue_name);
+
+        id
+    }
+
+    /// Sorted set of the names of a project's queues. All names are scored 0,
+    /// so they are ordered alphabetically and can be paged with ZRANGEBYLEX.
+    pub fn get_project_queues_key(project_id: &String) -> String {
+        let mut key = String::new();
+        key.push_str(DEFAULT_PROJECT_KEY);
+        key.push_str(project_id);
+        key.push_str(":queues");
+
+        key
+    }
+
+    /// Project ids and queue names are joined with `:` in ids and keys, so they cannot contain it.
+    pub fn is_valid_name(name: &String) -> bool {
+        !name.contains(':')
+    }
+
+    /// Keys of project queues are `project:<project_id>:queue:<name>`, a prefix that keys of
+    /// queues created before projects, `queue:<name>`, never have.
     pub fn get_queue_key(queue_id: &String) -> String {
         let mut key = String::new();
-        key.push_str(DEFAULT_QUEUE_KEY);
-        key.push_str(&queue_id);
+        match queue_id.find(':') {
+            Some(i) => {
+                key.push_str(DEFAULT_PROJECT_KEY);
+                key.push_str(&queue_id[..i]);
+                key.push_str(PROJECT_QUEUE_KEY);
+                key.push_str(&queue_id[i + 1..]);
+            },
+            None => {
+                key.push_str(DEFAULT_QUEUE_KEY);
+                key.push_str(&queue_id);
+            }
+        }
 
         key
     }
-}
\ No newline at end of file
+
+    /// Id of the queue stored under `key`, the reverse of `get_queue_key`.
+    pub fn get_queue_id_from_key(key: &str) -> Option<String> {
+        if key.starts_with(DEFAULT_PROJECT_KEY) {
+            let project_queue = &key[DEFAULT_PROJECT_KEY.len()..];
+            let i = project_queue.find(':')?;
+            let (project_id, name) = project_queue.split_at(i);
+            if project_id.is_empty() || !name.starts_with(PROJECT_QUEUE_KEY) || name.len() == PROJECT_QUEUE_KEY.len() {
+                return None;
+            }
+
+            Some(Queue::get_queue_id(&project_id.to_string(), &name[PROJECT_QUEUE_KEY.len()..].to_string()))
+        } else if key.starts_with(DEFAULT_QUEUE_KEY) && key.len() > DEFAULT_QUEUE_KEY.len() {
+            Some(key[DEFAULT_QUEUE_KEY.len()..].to_string())
+        } else {
+            None
+        }
+    }
+
+    pub fn get_message_history_key(queue_id: &String, message_id: &String) -> String {
+        let mut key = Queue::get_queue_key(queue_id);
+        key.push_str(":history:");
+        key.push_str(message_id);
+
+        key
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn project_queue_keys_do_not_overlap_legacy_keys() {
+        let project_queue = Queue::get_queue_id(&String::from("p1"), &String::from("emails"));
+        assert_eq!(Queue::get_queue_key(&project_queue), "project:p1:queue:emails");
+        assert_eq!(Queue::get_queue_key(&String::from("p1")), "queue:p1");
+        assert!(!Queue::get_queue_key(&project_queue).starts_with(DEFAULT_QUEUE_KEY));
+    }
+
+    #[test]
+    fn queue_id_is_read_from_key() {
+        assert_eq!(Queue::get_queue_id_from_key("project:p1:queue:emails"), Some(String::from("p1:emails")));
+        assert_eq!(Queue::get_queue_id_from_key("queue:emails"), Some(String::from("emails")));
+        assert_eq!(Queue::get_queue_id_from_key("project:p1:queues"), None);
+        assert_eq!(Queue::get_queue_id_from_key("project:p1:queue:"), None);
+        assert_eq!(Queue::get_queue_id_from_key("emails"), None);
+    }
+
+    #[test]
+    fn names_cannot_contain_separator() {
+        assert!(Queue::is_valid_name(&String::from("emails")));
+        assert!(!Queue::is_valid_name(&String::from("b:c")));
+    }
+}
@@ -1,7 +1,11 @@
 use std::collections::HashMap;
+use queue::Queue;
 
-const MESSAGE_TIMEOUT: u32 = 60;
-const MESSAGE_EXPIRATION: u32 = 604800;
+pub const MESSAGE_TIMEOUT: u32 = 60;
+pub const MAXIMUM_MESSAGE_TIMEOUT: u32 = 86400;
+pub const MESSAGE_EXPIRATION: u32 = 604800;
+pub const DEDUP_WINDOW: u32 = 300;
+pub const COMPRESSION_THRESHOLD: u32 = 65536;
 const RETRIES: u32 = 3;
 const RETRIES_DELAY: u32 = 60;
 
@@ -18,6 +22,7 @@ pub enum QueueState {
     Valid,
     TypeError,
     SubscriberError,
+    NameError,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -28,6 +33,14 @@ pub struct QueueInfo {
     #[serde(skip_serializing_if = "Option::is_none")] pub message_expiration: Option<u32>,
     #[serde(skip_serializing_if = "Option::is_none")] pub size: Option<usize>,
     #[serde(skip_serializing_if = "Option::is_none")] pub total_messages: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")] pub expired_messages: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")] pub max_reservations: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")] pub dead_letter_queue: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")] pub dedup_window: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")] pub compression_threshold: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")] pub history: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")] pub compressed_bytes: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")] pub uncompressed_bytes: Option<usize>,
     #[serde(skip_serializing_if = "Option::is_none")] pub push: Option<PushInfo>,
     #[serde(skip_serializing_if = "Option::is_none")] pub alerts: Option<Vec<Alert>>,
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -45,6 +58,14 @@ impl QueueInfo {
             queue_type: Some(QueueType::Pull),
             size: None,
             total_messages: None,
+            expired_messages: None,
+            max_reservations: None,
+            dead_letter_queue: None,
+            dedup_window: None,
+            compression_threshold: None,
+            history: None,
+            compressed_bytes: None,
+            uncompressed_bytes: None,
             push: None,
             alerts: None,
         }
@@ -59,6 +80,14 @@ impl QueueInfo {
             queue_type: Some(QueueType::Pull),
             size: None,
             total_messages: None,
+            expired_messages: None,
+            max_reservations: None,
+            dead_letter_queue: None,
+            dedup_window: Some(DEDUP_WINDOW),
+            compression_threshold: Some(COMPRESSION_THRESHOLD),
+            history: None,
+            compressed_bytes: None,
+            uncompressed_bytes: None,
             push: None,
             alerts: None,
         }
@@ -73,6 +102,14 @@ impl QueueInfo {
             self.message_expiration(MESSAGE_EXPIRATION);
         }
 
+        if self.dedup_window.is_none() {
+            self.dedup_window(DEDUP_WINDOW);
+        }
+
+        if self.compression_threshold.is_none() {
+            self.compression_threshold(COMPRESSION_THRESHOLD);
+        }
+
         if self.queue_type.is_none() {
             self.queue_type(QueueType::Pull);
         }
@@ -116,6 +153,48 @@ impl QueueInfo {
         self
     }
 
+    pub fn max_reservations(&mut self, max_reservations: u32) -> &mut QueueInfo {
+        self.max_reservations = Some(max_reservations);
+
+        self
+    }
+
+    pub fn dead_letter_queue(&mut self, dead_letter_queue: String) -> &mut QueueInfo {
+        self.dead_letter_queue = Some(dead_letter_queue);
+
+        self
+    }
+
+    pub fn dedup_window(&mut self, dedup_window: u32) -> &mut QueueInfo {
+        self.dedup_window = Some(dedup_window);
+
+        self
+    }
+
+    pub fn compression_threshold(&mut self, compression_threshold: u32) -> &mut QueueInfo {
+        self.compression_threshold = Some(compression_threshold);
+
+        self
+    }
+
+    pub fn history(&mut self, history: bool) -> &mut QueueInfo {
+        self.history = Some(history);
+
+        self
+    }
+
+    pub fn compressed_bytes(&mut self, compressed_bytes: usize) -> &mut QueueInfo {
+        self.compressed_bytes = Some(compressed_bytes);
+
+        self
+    }
+
+    pub fn uncompressed_bytes(&mut self, uncompressed_bytes: usize) -> &mut QueueInfo {
+        self.uncompressed_bytes = Some(uncompressed_bytes);
+
+        self
+    }
+
     pub fn queue_type(&mut self, queue_type: QueueType) -> &mut QueueInfo {
         self.queue_type = Some(queue_type);
 
@@ -146,6 +225,26 @@ impl QueueInfo {
         self
     }
 
+    pub fn expired_messages(&mut self, expired_messages: usize) -> &mut QueueInfo {
+        self.expired_messages = Some(expired_messages);
+
+        self
+    }
+
+    /// Id the queue is stored under, see `Queue::get_queue_id`. Queues created before
+    /// projects were namespaced have no project id and are stored under their name.
+    pub fn queue_id(&self) -> String {
+        self.project_queue_id(self.name.as_ref().unwrap())
+    }
+
+    /// Id of another queue of the same project, such as the error or dead-letter queue.
+    pub fn project_queue_id(&self, queue_name: &String) -> String {
+        match self.project_id {
+            Some(ref project_id) => Queue::get_queue_id(project_id, queue_name),
+            None => queue_name.clone()
+        }
+    }
+
     pub fn is_pull(&mut self) -> Option<bool> {
         match &self.queue_type {
             Some(queue_type) => Some(queue_type == &QueueType::Pull),
@@ -154,6 +253,15 @@ impl QueueInfo {
     }
 
     pub fn state(&mut self) -> QueueState {
+        let names_valid = self.project_id.iter()
+            .chain(self.name.iter())
+            .chain(self.dead_letter_queue.iter())
+            .chain(self.push.iter().filter_map(|push| push.error_queue.as_ref()))
+            .all(|name| Queue::is_valid_name(name));
+        if !names_valid {
+            return QueueState::NameError;
+        }
+
         match self.is_pull() {
             Some(is_pull) => {
                 if is_pull {
@@ -289,3 +397,32 @@ pub struct PushStatus {
     pub url: String,
     pub msg: Option<String>
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn queue_ids_are_namespaced_by_project() {
+        let mut queue_info = QueueInfo::new(String::from("emails"));
+        assert_eq!(queue_info.queue_id(), "emails");
+
+        queue_info.project_id = Some(String::from("p1"));
+        assert_eq!(queue_info.queue_id(), "p1:emails");
+        assert_eq!(queue_info.project_queue_id(&String::from("errors")), "p1:errors");
+    }
+
+    #[test]
+    fn names_with_separator_are_rejected() {
+        let mut queue_info = QueueInfo::default(String::from("emails"));
+        queue_info.project_id = Some(String::from("p1"));
+        assert!(match queue_info.state() { QueueState::Valid => true, _ => false });
+
+        queue_info.project_id = Some(String::from("p1:emails"));
+        assert!(match queue_info.state() { QueueState::NameError => true, _ => false });
+
+        queue_info.project_id = Some(String::from("p1"));
+        queue_info.dead_letter_queue = Some(String::from("dead:letters"));
+        assert!(match queue_info.state() { QueueState::NameError => true, _ => false });
+    }
+}
@@ -2,6 +2,7 @@ extern crate mime;
 extern crate serde_json;
 
 use serde_json::Value;
+use chrono::prelude::*;
 use hyper::{Body, StatusCode};
 use futures::{future, Future, Stream};
 use gotham::{
@@ -15,12 +16,21 @@ use gotham::{
 };
 use middleware::redis::RedisPool;
 use api::queue::QueuePathExtractor;
-use mq::message::{MAXIMUM_NUMBER_TO_PEEK};
-use queue::message::Message;
+use mq::message::{DeleteOutcome, TouchOutcome, MAXIMUM_NUMBER_TO_PEEK, MAXIMUM_PEEK_SIZE, MAXIMUM_DELAY, MAXIMUM_BATCH_SIZE, MAXIMUM_NUMBER_TO_TRANSFER};
+use queue::{
+    message::{MessageFilter, MessageState},
+    queue::Queue,
+    queue_info::MAXIMUM_MESSAGE_TIMEOUT
+};
+use failure::Error;
+use std::cmp;
 
 #[derive(Deserialize, StateData, StaticResponseExtender)]
 pub struct QueryStringExtractor {
     n: Option<i32>,
+    cursor: Option<String>,
+    after: Option<String>,
+    state: Option<String>,
 }
 
 pub fn delete(mut state: State) -> Box<HandlerFuture> {
@@ -36,35 +46,27 @@ pub fn delete(mut state: State) -> Box<HandlerFuture> {
 
                     let (queue_name, message_id): (String, String) = {
                         let path = QueuePathExtractor::borrow_from(&state);
-                        (path.name.clone().unwrap(), path.message_id.clone().unwrap())
-                    };
-
-                    let request_body :Result<Value, serde_json::Error> = serde_json::from_slice(&valid_body.to_vec());
-                    let message = ::mq::message::get_message(&queue_name, &message_id, &connection);
-
-                    let (response_message, status_code) = match &message {
-                        Ok(message) => {
-                            match request_body {
-                                Ok(request_body) => {
-                                    let reservation_id = request_body["reservation_id"].as_str().unwrap_or("");
-                                    let request_message = MessageDeleteBodyRequest {
-                                        id: message_id.clone(),
-                                        reservation_id: Some(reservation_id.to_string()),
-                                    };
-                                    match request_message.is_valid_for(&message) {
-                                                true => {
-                                                    match ::mq::message::delete(queue_name, message_id, &connection) {
-                                                        Ok(_deleted) => ("Deleted", StatusCode::Ok),
-                                                        Err(_) => ("Message not found", StatusCode::NotFound)
-                                                    }
-                                                },
-                                                false => ("A reservation_id is required", StatusCode::BadRequest),
-                                            }
-                                },
-                                Err(_) => ("Failed to decode JSON.", StatusCode::BadRequest)       
+                        (path.queue_id(), path.message_id.clone().unwrap())
+                    };
+
+                    // Messages that are not reserved, such as scheduled ones, can be deleted without a body.
+                    let request_body :Result<Value, serde_json::Error> = if valid_body.is_empty() {
+                        Ok(json!({}))
+                    } else {
+                        serde_json::from_slice(&valid_body.to_vec())
+                    };
+                    let (response_message, status_code) = match request_body {
+                        Ok(request_body) => {
+                            let reservation_id = request_body["reservation_id"].as_str().unwrap_or("");
+                            match ::mq::message::delete(queue_name.clone(), message_id, Some(reservation_id.to_string()), &connection) {
+                                Ok(outcome) => delete_response(outcome),
+                                Err(e) => {
+                                    error!("Queue {:?}: message not deleted: {:?}", queue_name, e.to_string());
+                                    delete_response(DeleteOutcome::Error)
+                                }
                             }
                         },
-                        Err(_) => ("Message not found", StatusCode::NotFound),
+                        Err(_) => ("Failed to decode JSON.", StatusCode::BadRequest)
                     };
 
                     let body = json!({
@@ -93,25 +95,36 @@ pub struct MessageDeleteBodyRequest{
     pub reservation_id: Option<String>
 }
 
-impl MessageDeleteBodyRequest {
-    pub fn is_valid_for(&self, message: &Message) -> bool {
-        let is_reserved = match message.is_reserved() {
-            Some(is_reserved) => is_reserved,
-            None => false,
-        };
-
-        let is_valid = if is_reserved {
-            match &self.reservation_id {
-                Some(reservation_id) => {
-                    reservation_id == &message.clone().reservation_id.unwrap()
-                },
-                None => false,
-            }
-        } else {
-            true
-        };
+#[derive(Debug, Serialize)]
+pub struct MessageDeleteResult {
+    pub id: String,
+    pub outcome: DeleteOutcome
+}
+
+fn delete_response(outcome: DeleteOutcome) -> (&'static str, StatusCode) {
+    match outcome {
+        DeleteOutcome::Deleted => ("Deleted", StatusCode::Ok),
+        DeleteOutcome::NotFound => ("Message not found", StatusCode::NotFound),
+        DeleteOutcome::ReservationRequired => ("A reservation_id is required", StatusCode::BadRequest),
+        DeleteOutcome::StaleReservation => ("Reservation is stale", StatusCode::Conflict),
+        DeleteOutcome::Error => ("Message could not be deleted", StatusCode::InternalServerError)
+    }
+}
 
-        is_valid
+/// Sums up the outcomes of a batch delete. When no message was deleted for the same
+/// reason, the response is the one for that reason.
+fn delete_batch_response(outcomes: &[DeleteOutcome]) -> (&'static str, StatusCode) {
+    let deleted = outcomes.iter().filter(|outcome| **outcome == DeleteOutcome::Deleted).count();
+    if deleted == outcomes.len() {
+        return ("Deleted", StatusCode::Ok);
+    }
+    if deleted > 0 {
+        return ("Some messages were not deleted", StatusCode::Ok);
+    }
+
+    match outcomes.split_first() {
+        Some((first, rest)) if rest.iter().all(|outcome| outcome == first) => delete_response(*first),
+        _ => ("No messages were deleted", StatusCode::Ok)
     }
 }
 
@@ -128,7 +141,7 @@ pub fn delete_messages(mut state: State) -> Box<HandlerFuture> {
 
                     let queue_name = {
                         let path = QueuePathExtractor::borrow_from(&state);
-                        path.name.clone().unwrap()
+                        path.queue_id()
                     };
 
                     let body_content: Value = serde_json::from_slice(&valid_body.to_vec()).unwrap();
@@ -184,25 +197,64 @@ pub fn delete_messages(mut state: State) -> Box<HandlerFuture> {
                             return future::ok((state, res));
                         }
                     };
-                    
-                    let queue = queue_name.clone();
-                    let mut invalid_messages = messages.clone().into_iter().filter(|req_message| {
-                        let message = ::mq::message::get_message(&queue, &req_message.id, &connection);
-                        match &message {
-                            Ok(m) => !req_message.is_valid_for(m),
-                            Err(_) => false
-                        }
+
+                    // Each message is deleted on its own, the outcome of every one is reported.
+                    let outcomes = ::mq::message::delete_messages(queue_name, &messages, &connection);
+                    let (msg, status_code) = delete_batch_response(&outcomes);
+                    let results: Vec<MessageDeleteResult> = messages.iter()
+                        .zip(outcomes.into_iter())
+                        .map(|(message, outcome)| MessageDeleteResult {
+                            id: message.id.clone(),
+                            outcome
+                        })
+                        .collect();
+                    let body = json!({
+                        "ids": results,
+                        "msg": msg
                     });
 
-                    match invalid_messages.next() {
-                        Some(_invalid_message) => {
+                    let res = create_response(
+                        &state,
+                        status_code,
+                        Some((
+                            body.to_string().into_bytes(),
+                            mime::APPLICATION_JSON
+                        ))
+                    );
+
+                    return future::ok((state, res));
+                },
+                Err(e) => future::err((state, e.into_handler_error()))
+            });
+
+        Box::new(f)
+}
+
+pub fn get_message(mut state: State) -> Box<HandlerFuture> {
+        let f = Body::take_from(&mut state)
+            .concat2()
+            .then(|full_body| match full_body {
+                Ok(_valid_body) => {
+                    let connection = {
+                        let redis_pool = RedisPool::borrow_mut_from(&mut state);
+                        let connection = redis_pool.conn().unwrap();
+                        connection
+                    };
+
+                    let (queue_name, message_id): (String, String) = {
+                        let path = QueuePathExtractor::borrow_from(&state);
+                        (path.queue_id(), path.message_id.clone().unwrap())
+                    };
+
+                    match ::mq::message::get_message(&queue_name, &message_id, &connection) {
+                        Ok(msg) => {
                             let body = json!({
-                                "msg": "A reservation_id is required"
+                                "message": msg
                             });
 
                             let res = create_response(
                                 &state,
-                                StatusCode::BadRequest,
+                                StatusCode::Ok,
                                 Some((
                                     body.to_string().into_bytes(),
                                     mime::APPLICATION_JSON
@@ -211,28 +263,20 @@ pub fn delete_messages(mut state: State) -> Box<HandlerFuture> {
 
                             return future::ok((state, res));
                         },
-                        None => (),
-                    };
-
-                    match ::mq::message::delete_messages(queue_name, &messages, &connection) {
-                        Ok(_deleted) => {
+                        Err(e) => {
                             let body = json!({
-                                "msg": "Deleted"
+                                "msg": e.to_string()
                             });
 
                             let res = create_response(
                                 &state,
-                                StatusCode::Ok,
+                                StatusCode::NotFound,
                                 Some((
                                     body.to_string().into_bytes(),
                                     mime::APPLICATION_JSON
                                 ))
                             );
 
-                            return future::ok((state, res));
-                        },
-                        Err(_e) => {
-                            let res = create_response(&state, StatusCode::NotFound, None);
                             return future::ok((state, res));
                         }
                     }
@@ -243,11 +287,11 @@ pub fn delete_messages(mut state: State) -> Box<HandlerFuture> {
         Box::new(f)
 }
 
-pub fn get_message(mut state: State) -> Box<HandlerFuture> {
+pub fn touch_message(mut state: State) -> Box<HandlerFuture> {
         let f = Body::take_from(&mut state)
             .concat2()
             .then(|full_body| match full_body {
-                Ok(_valid_body) => {
+                Ok(valid_body) => {
                     let connection = {
                         let redis_pool = RedisPool::borrow_mut_from(&mut state);
                         let connection = redis_pool.conn().unwrap();
@@ -256,13 +300,40 @@ pub fn get_message(mut state: State) -> Box<HandlerFuture> {
 
                     let (queue_name, message_id): (String, String) = {
                         let path = QueuePathExtractor::borrow_from(&state);
-                        (path.name.clone().unwrap(), path.message_id.clone().unwrap())
+                        (path.queue_id(), path.message_id.clone().unwrap())
                     };
 
-                    match ::mq::message::get_message(&queue_name, &message_id, &connection) {
-                        Ok(msg) => {
+                    let body_content: Value = serde_json::from_slice(&valid_body.to_vec()).unwrap();
+                    let old_reservation_id: String = serde_json::from_value(body_content["reservation_id"].clone()).unwrap();
+                    let timeout = match body_content["timeout"] {
+                        Value::Null => None,
+                        ref timeout => match timeout.as_u64() {
+                            Some(timeout) if timeout >= 1 && timeout <= MAXIMUM_MESSAGE_TIMEOUT as u64 => Some(timeout as u32),
+                            _ => {
+                                let body = json!({
+                                    "msg": format!("Timeout must be between 1 and {} seconds", MAXIMUM_MESSAGE_TIMEOUT)
+                                });
+
+                                let res = create_response(
+                                    &state,
+                                    StatusCode::BadRequest,
+                                    Some((
+                                        body.to_string().into_bytes(),
+                                        mime::APPLICATION_JSON
+                                    ))
+                                );
+
+                                return future::ok((state, res));
+                            }
+                        }
+                    };
+
+                    match ::mq::message::touch_message(&queue_name, &message_id, &old_reservation_id, timeout, &connection) {
+                        Ok(TouchOutcome::Touched(reservation_id, reserved_until)) => {
                             let body = json!({
-                                "message": msg
+                                "reservation_id": reservation_id,
+                                "reserved_until": Utc.timestamp(reserved_until, 0).to_rfc3339(),
+                                "msg": "Touched"
                             });
 
                             let res = create_response(
@@ -276,14 +347,14 @@ pub fn get_message(mut state: State) -> Box<HandlerFuture> {
 
                             return future::ok((state, res));
                         },
-                        Err(e) => {
+                        Ok(TouchOutcome::StaleReservation) => {
                             let body = json!({
-                                "msg": e.to_string()
+                                "msg": "Reservation is stale"
                             });
 
                             let res = create_response(
                                 &state,
-                                StatusCode::NotFound,
+                                StatusCode::Conflict,
                                 Some((
                                     body.to_string().into_bytes(),
                                     mime::APPLICATION_JSON
@@ -291,6 +362,10 @@ pub fn get_message(mut state: State) -> Box<HandlerFuture> {
                             );
 
                             return future::ok((state, res));
+                        },
+                        Ok(TouchOutcome::NotFound) | Err(_) => {
+                            let res = create_response(&state, StatusCode::NotFound, None);
+                            return future::ok((state, res))
                         }
                     }
                 },
@@ -300,30 +375,82 @@ pub fn get_message(mut state: State) -> Box<HandlerFuture> {
         Box::new(f)
 }
 
-pub fn touch_message(mut state: State) -> Box<HandlerFuture> {
+pub fn peek_messages(mut state: State) -> Box<HandlerFuture> {
         let f = Body::take_from(&mut state)
             .concat2()
             .then(|full_body| match full_body {
-                Ok(valid_body) => {
+                Ok(_valid_body) => {
                     let connection = {
                         let redis_pool = RedisPool::borrow_mut_from(&mut state);
                         let connection = redis_pool.conn().unwrap();
                         connection
                     };
 
-                    let (queue_name, message_id): (String, String) = {
+                    let queue_name: String = {
                         let path = QueuePathExtractor::borrow_from(&state);
-                        (path.name.clone().unwrap(), path.message_id.clone().unwrap())
+                        path.queue_id()
                     };
 
-                    let body_content: Value = serde_json::from_slice(&valid_body.to_vec()).unwrap();
-                    let old_reservation_id: String = serde_json::from_value(body_content["reservation_id"].clone()).unwrap();
+                    let (n, cursor, message_state): (i32, Option<String>, Option<String>) = {
+                        let query = QueryStringExtractor::borrow_from(&state);
+                        let n = match query.n {
+                            Some(n) => n,
+                            None => MAXIMUM_NUMBER_TO_PEEK,
+                        };
+                        (n, query.cursor.clone().or(query.after.clone()), query.state.clone())
+                    };
 
-                    match ::mq::message::touch_message(&queue_name, &message_id, &old_reservation_id, &connection) {
-                        Ok(reservation_id) => {
+                    let message_state: Option<MessageState> = match message_state.as_ref().map(|s| s.as_str()) {
+                        None | Some("unreserved") => Some(MessageState::Unreserved),
+                        Some("reserved") => Some(MessageState::Reserved),
+                        Some("delayed") | Some("scheduled") => Some(MessageState::Delayed),
+                        Some("all") => None,
+                        Some(_) => {
                             let body = json!({
-                                "reservation_id": reservation_id,
-                                "msg": "Touched"
+                                "msg": "State must be one of reserved, unreserved, delayed, scheduled or all"
+                            });
+
+                            let res = create_response(
+                                &state,
+                                StatusCode::BadRequest,
+                                Some((
+                                    body.to_string().into_bytes(),
+                                    mime::APPLICATION_JSON
+                                ))
+                            );
+
+                            return future::ok((state, res));
+                        }
+                    };
+
+                    if n < 1 || n > MAXIMUM_PEEK_SIZE {
+                        let body = json!({
+                            "msg": format!("n must be between 1 and {}", MAXIMUM_PEEK_SIZE)
+                        });
+
+                        let res = create_response(
+                            &state,
+                            StatusCode::BadRequest,
+                            Some((
+                                body.to_string().into_bytes(),
+                                mime::APPLICATION_JSON
+                            ))
+                        );
+
+                        return future::ok((state, res));
+                    }
+
+                    match ::mq::message::peek_messages(&queue_name, &n, cursor.as_ref(), message_state, &connection) {
+                        Ok(msgs) => {
+                            // A full page may be followed by more messages.
+                            let next_cursor = if msgs.len() == n as usize {
+                                msgs.last().and_then(|msg| msg.id.clone())
+                            } else {
+                                None
+                            };
+                            let body = json!({
+                                "messages": msgs,
+                                "cursor": next_cursor
                             });
 
                             let res = create_response(
@@ -339,7 +466,7 @@ pub fn touch_message(mut state: State) -> Box<HandlerFuture> {
                         },
                         Err(_e) => {
                             let res = create_response(&state, StatusCode::NotFound, None);
-                            return future::ok((state, res))
+                            return future::ok((state, res));
                         }
                     }
                 },
@@ -349,7 +476,7 @@ pub fn touch_message(mut state: State) -> Box<HandlerFuture> {
         Box::new(f)
 }
 
-pub fn peek_messages(mut state: State) -> Box<HandlerFuture> {
+pub fn list_reservations(mut state: State) -> Box<HandlerFuture> {
         let f = Body::take_from(&mut state)
             .concat2()
             .then(|full_body| match full_body {
@@ -362,21 +489,45 @@ pub fn peek_messages(mut state: State) -> Box<HandlerFuture> {
 
                     let queue_name: String = {
                         let path = QueuePathExtractor::borrow_from(&state);
-                        path.name.clone().unwrap()
+                        path.queue_id()
                     };
 
-                    let n: i32 = {
-                        let path = QueryStringExtractor::borrow_from(&state);
-                        match path.n {
+                    let (n, cursor): (i32, Option<String>) = {
+                        let query = QueryStringExtractor::borrow_from(&state);
+                        let n = match query.n {
                             Some(n) => n,
-                            None => MAXIMUM_NUMBER_TO_PEEK,
-                        }
+                            None => MAXIMUM_PEEK_SIZE,
+                        };
+                        (n, query.cursor.clone())
                     };
 
-                    match ::mq::message::peek_messages(&queue_name, &n, &connection) {
-                        Ok(msgs) => {
+                    if n < 1 || n > MAXIMUM_PEEK_SIZE {
+                        let body = json!({
+                            "msg": format!("n must be between 1 and {}", MAXIMUM_PEEK_SIZE)
+                        });
+
+                        let res = create_response(
+                            &state,
+                            StatusCode::BadRequest,
+                            Some((
+                                body.to_string().into_bytes(),
+                                mime::APPLICATION_JSON
+                            ))
+                        );
+
+                        return future::ok((state, res));
+                    }
+
+                    match ::mq::message::list_reservations(&queue_name, &n, cursor.as_ref(), &connection) {
+                        Ok(reservations) => {
+                            let next_cursor = if reservations.len() == n as usize {
+                                reservations.last().map(|reservation| reservation.id.clone())
+                            } else {
+                                None
+                            };
                             let body = json!({
-                                "messages": msgs
+                                "reservations": reservations,
+                                "cursor": next_cursor
                             });
 
                             let res = create_response(
@@ -416,13 +567,30 @@ pub fn release_message(mut state: State) -> Box<HandlerFuture> {
 
                     let (queue_name, message_id): (String, String) = {
                         let path = QueuePathExtractor::borrow_from(&state);
-                        (path.name.clone().unwrap(), path.message_id.clone().unwrap())
+                        (path.queue_id(), path.message_id.clone().unwrap())
                     };
 
                     let body_content: Value = serde_json::from_slice(&valid_body.to_vec()).unwrap();
                     let reservation_id: String = serde_json::from_value(body_content["reservation_id"].clone()).unwrap();
+                    let delay = body_content["delay"].as_u64().unwrap_or(0);
+                    if delay > MAXIMUM_DELAY as u64 {
+                        let body = json!({
+                            "msg": format!("Delay must be between 0 and {}", MAXIMUM_DELAY)
+                        });
+
+                        let res = create_response(
+                            &state,
+                            StatusCode::BadRequest,
+                            Some((
+                                body.to_string().into_bytes(),
+                                mime::APPLICATION_JSON
+                            ))
+                        );
 
-                    let released = ::mq::message::release_message(&queue_name, &message_id, &reservation_id, &connection).unwrap();
+                        return future::ok((state, res))
+                    }
+
+                    let released = ::mq::message::release_message(&queue_name, &message_id, &reservation_id, delay as u32, &connection).unwrap();
 
                     if !released {
                         let res = create_response(&state, StatusCode::NotFound, None);
@@ -464,7 +632,7 @@ pub fn get_push_statuses(mut state: State) -> Box<HandlerFuture> {
 
                     let (queue_name, message_id): (String, String) = {
                         let path = QueuePathExtractor::borrow_from(&state);
-                        (path.name.clone().unwrap(), path.message_id.clone().unwrap())
+                        (path.queue_id(), path.message_id.clone().unwrap())
                     };
 
                     match ::mq::message::get_push_statuses(&queue_name, &message_id, &connection) {
@@ -507,3 +675,220 @@ pub fn get_push_statuses(mut state: State) -> Box<HandlerFuture> {
 
         Box::new(f)
 }
+
+pub fn get_message_history(mut state: State) -> Box<HandlerFuture> {
+        let f = Body::take_from(&mut state)
+            .concat2()
+            .then(|full_body| match full_body {
+                Ok(_valid_body) => {
+                    let connection = {
+                        let redis_pool = RedisPool::borrow_mut_from(&mut state);
+                        let connection = redis_pool.conn().unwrap();
+                        connection
+                    };
+
+                    let (queue_name, message_id): (String, String) = {
+                        let path = QueuePathExtractor::borrow_from(&state);
+                        (path.queue_id(), path.message_id.clone().unwrap())
+                    };
+
+                    let (body, status_code) = match ::mq::message::get_message_history(&queue_name, &message_id, &connection) {
+                        Ok(ref history) if history.is_empty() => (json!({ "msg": "History not found" }), StatusCode::NotFound),
+                        Ok(history) => (json!({ "history": history }), StatusCode::Ok),
+                        Err(e) => (json!({ "msg": e.to_string() }), StatusCode::NotFound)
+                    };
+
+                    let res = create_response(
+                        &state,
+                        status_code,
+                        Some((
+                            body.to_string().into_bytes(),
+                            mime::APPLICATION_JSON
+                        ))
+                    );
+
+                    return future::ok((state, res));
+                },
+                Err(e) => future::err((state, e.into_handler_error()))
+            });
+
+        Box::new(f)
+}
+
+/// Body of move and copy requests. Messages are given by `ids`, or selected by `filter`, up to `n` of them.
+#[derive(Debug, Deserialize)]
+pub struct TransferMessagesRequest {
+    pub to: String,
+    pub ids: Option<Vec<String>>,
+    pub filter: Option<MessageFilter>,
+    pub n: Option<usize>
+}
+
+pub fn move_messages(state: State) -> Box<HandlerFuture> {
+    transfer_messages(state, false)
+}
+
+pub fn copy_messages(state: State) -> Box<HandlerFuture> {
+    transfer_messages(state, true)
+}
+
+fn transfer_messages(mut state: State, copy: bool) -> Box<HandlerFuture> {
+        let f = Body::take_from(&mut state)
+            .concat2()
+            .then(move |full_body| match full_body {
+                Ok(valid_body) => {
+                    let connection = {
+                        let redis_pool = RedisPool::borrow_mut_from(&mut state);
+                        let connection = redis_pool.conn().unwrap();
+                        connection
+                    };
+
+                    let (queue_name, project_id) = {
+                        let path = QueuePathExtractor::borrow_from(&state);
+                        (path.queue_id(), path.project_id.clone())
+                    };
+
+                    let request: TransferMessagesRequest = match serde_json::from_slice(&valid_body.to_vec()) {
+                        Ok(request) => request,
+                        Err(_) => {
+                            let body = json!({
+                                "msg": "Failed to decode JSON."
+                            });
+                            let res = create_response(
+                                &state,
+                                StatusCode::BadRequest,
+                                Some((
+                                    body.to_string().into_bytes(),
+                                    mime::APPLICATION_JSON
+                                ))
+                            );
+                            return future::ok((state, res));
+                        }
+                    };
+
+                    let destination = Queue::get_queue_id(&project_id, &request.to);
+                    let too_many_ids = request.ids.as_ref().map(|ids| ids.len() > MAXIMUM_BATCH_SIZE).unwrap_or(false);
+
+                    let (body, status_code) = if too_many_ids {
+                        (json!({ "msg": format!("At most {} messages can be given by id", MAXIMUM_BATCH_SIZE) }), StatusCode::BadRequest)
+                    } else if destination == queue_name {
+                        (json!({ "msg": "Destination must be another queue" }), StatusCode::BadRequest)
+                    } else if ::mq::queue::get_queue_info(destination.clone(), &connection).is_err() {
+                        (json!({ "msg": "Destination queue not found" }), StatusCode::NotFound)
+                    } else {
+                        let message_ids: Result<Vec<String>, Error> = match request.ids {
+                            Some(ids) => Ok(ids),
+                            None => {
+                                let filter = request.filter.unwrap_or_default();
+                                let n = cmp::min(request.n.unwrap_or(MAXIMUM_NUMBER_TO_TRANSFER), MAXIMUM_BATCH_SIZE);
+                                ::mq::message::find_messages(&queue_name, &filter, n, &connection)
+                                    .map(|msgs| msgs.into_iter().filter_map(|msg| msg.id).collect())
+                            }
+                        };
+
+                        let transferred = message_ids.and_then(|message_ids| {
+                            ::mq::message::transfer_messages(&queue_name, &destination, &message_ids, copy, &connection)
+                        });
+
+                        match transferred {
+                            Ok(transferred) => {
+                                let ids: Vec<Value> = transferred.into_iter()
+                                    .map(|(id, new_id)| json!({ "id": id, "new_id": new_id }))
+                                    .collect();
+                                (json!({ "ids": ids, "msg": if copy { "Copied" } else { "Moved" } }), StatusCode::Ok)
+                            },
+                            Err(e) => (json!({ "msg": e.to_string() }), StatusCode::InternalServerError)
+                        }
+                    };
+
+                    let res = create_response(
+                        &state,
+                        status_code,
+                        Some((
+                            body.to_string().into_bytes(),
+                            mime::APPLICATION_JSON
+                        ))
+                    );
+
+                    return future::ok((state, res));
+                },
+                Err(e) => future::err((state, e.into_handler_error()))
+            });
+
+        Box::new(f)
+}
+
+/// Body of purge requests. Without a filter, all messages that are not reserved are purged.
+#[derive(Debug, Deserialize)]
+pub struct PurgeMessagesRequest {
+    pub filter: Option<MessageFilter>,
+    pub pushed_before: Option<String>
+}
+
+pub fn purge_messages(mut state: State) -> Box<HandlerFuture> {
+        let f = Body::take_from(&mut state)
+            .concat2()
+            .then(|full_body| match full_body {
+                Ok(valid_body) => {
+                    let connection = {
+                        let redis_pool = RedisPool::borrow_mut_from(&mut state);
+                        let connection = redis_pool.conn().unwrap();
+                        connection
+                    };
+
+                    let queue_name = {
+                        let path = QueuePathExtractor::borrow_from(&state);
+                        path.queue_id()
+                    };
+
+                    let request: Option<PurgeMessagesRequest> = serde_json::from_slice(&valid_body.to_vec()).ok();
+                    let pushed_before: Option<Result<i64, ()>> = request.as_ref()
+                        .and_then(|request| request.pushed_before.as_ref())
+                        .map(|pushed_before| DateTime::parse_from_rfc3339(pushed_before)
+                            .map(|pushed_before| pushed_before.timestamp())
+                            .map_err(|_| ()));
+
+                    let (body, status_code) = match (request, pushed_before) {
+                        (None, _) => (json!({ "msg": "Failed to decode JSON." }), StatusCode::BadRequest),
+                        (_, Some(Err(_))) => (json!({ "msg": "pushed_before must be an RFC3339 timestamp" }), StatusCode::BadRequest),
+                        (Some(request), pushed_before) => {
+                            let filter = request.filter.unwrap_or_default();
+                            match ::mq::message::purge_messages(&queue_name, &filter, pushed_before.map(|p| p.unwrap()), &connection) {
+                                Ok(purged) => (json!({ "purged": purged, "msg": "Purged" }), StatusCode::Ok),
+                                Err(e) => (json!({ "msg": e.to_string() }), StatusCode::InternalServerError)
+                            }
+                        }
+                    };
+
+                    let res = create_response(
+                        &state,
+                        status_code,
+                        Some((
+                            body.to_string().into_bytes(),
+                            mime::APPLICATION_JSON
+                        ))
+                    );
+
+                    return future::ok((state, res));
+                },
+                Err(e) => future::err((state, e.into_handler_error()))
+            });
+
+        Box::new(f)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn batch_delete_response_follows_the_outcomes() {
+        use mq::message::DeleteOutcome::*;
+
+        assert_eq!(("Deleted", StatusCode::Ok), delete_batch_response(&[Deleted, Deleted]));
+        assert_eq!(("Some messages were not deleted", StatusCode::Ok), delete_batch_response(&[Deleted, StaleReservation]));
+        assert_eq!(("Reservation is stale", StatusCode::Conflict), delete_batch_response(&[StaleReservation, StaleReservation]));
+        assert_eq!(("Message could not be deleted", StatusCode::InternalServerError), delete_batch_response(&[Error]));
+        assert_eq!(("No messages were deleted", StatusCode::Ok), delete_batch_response(&[NotFound, StaleReservation]));
+    }
+}
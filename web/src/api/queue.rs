@@ -1,7 +1,11 @@
 extern crate mime;
 extern crate serde_json;
 
-use hyper::{Body, StatusCode};
+use std::{
+    cmp,
+    time::{Duration, Instant}
+};
+use hyper::{Body, Headers, StatusCode, header::ContentType};
 use futures::{future, Future, Stream};
 use gotham::{
     handler::{
@@ -14,16 +18,28 @@ use gotham::{
     }
 };
 use serde_json::Value;
-use middleware::redis::RedisPool;
+use tokio::timer::Delay;
+use chrono::prelude::*;
+use middleware::{
+    redis::RedisPool,
+    notifier::QueueNotifier
+};
 use mq::{
     message::{
-        ReserveMessageParams
+        ReserveMessageParams,
+        MAXIMUM_DELAY,
+        MAXIMUM_NUMBER_TO_RESERVE,
+        MAXIMUM_WAIT
     },
-    queue::{create_queue, delete, get_queue, post_message, patch_queue_info}
+    queue::{
+        create_queue, delete, get_queue, post_message, patch_queue_info,
+        ListQueuesParams, QUEUES_PER_PAGE, MAXIMUM_QUEUES_PER_PAGE
+    }
 };
 use queue::{
-    queue_info::{QueueInfo, QueueSubscriber, QueueState},
-    message::*
+    queue_info::{QueueInfo, QueueSubscriber, QueueState, MAXIMUM_MESSAGE_TIMEOUT},
+    message::*,
+    queue::Queue
 };
 
 #[derive(Debug, Deserialize, StateData, StaticResponseExtender)]
@@ -33,6 +49,21 @@ pub struct QueuePathExtractor {
     pub message_id: Option<String>
 }
 
+#[derive(Debug, Deserialize, StateData, StaticResponseExtender)]
+pub struct ListQueuesQueryStringExtractor {
+    per_page: Option<usize>,
+    previous: Option<String>,
+    prefix: Option<String>,
+    full: Option<bool>
+}
+
+impl QueuePathExtractor {
+    /// Id of the queue in the path, see `Queue::get_queue_id`.
+    pub fn queue_id(&self) -> String {
+        Queue::get_queue_id(&self.project_id, self.name.as_ref().unwrap())
+    }
+}
+
 pub fn put_queue(mut state: State) -> Box<HandlerFuture> {
     let f = Body::take_from(&mut state)
         .concat2()
@@ -58,11 +89,11 @@ pub fn put_queue(mut state: State) -> Box<HandlerFuture> {
                     q.name = Some(name);
                     q.fill_missed_fields();
                 }
+                q.project_id = Some(project_id);
 
                 let (body, status_code): (Value, StatusCode) = match q.state() {
                     QueueState::Valid => {
-                        let mut queue = create_queue(q, &connection);
-                        queue.project_id = Some(project_id);
+                        let queue = create_queue(q, &connection);
                         let body = json!({
                             "queue": queue
                         });
@@ -79,6 +110,12 @@ pub fn put_queue(mut state: State) -> Box<HandlerFuture> {
 	                        "msg": "Push queues must have at least one subscriber"
                         });
                         (body, StatusCode::BadRequest)
+                    },
+                    QueueState::NameError => {
+                        let body = json!({
+                            "msg": "Project ids and queue names cannot contain ':'"
+                        });
+                        (body, StatusCode::BadRequest)
                     }
                 };
 
@@ -104,6 +141,120 @@ pub fn push_messages(mut state: State) -> Box<HandlerFuture> {
         .concat2()
         .then(|full_body| match full_body {
             Ok(valid_body) => {
+                let mut messages: Vec<Message> = {
+                    let body_content: Value = serde_json::from_slice(&valid_body.to_vec()).unwrap();
+                    serde_json::from_value(body_content["messages"].clone()).unwrap()
+                };
+
+                // A retried request carries the same Idempotency-Key, so every message in it
+                // gets a dedup id derived from the key and its position.
+                let idempotency_key: Option<String> = {
+                    let headers: &Headers = Headers::borrow_from(&state);
+                    headers.get_raw("Idempotency-Key")
+                        .and_then(|raw| raw.one())
+                        .and_then(|value| String::from_utf8(value.to_vec()).ok())
+                };
+                if let Some(key) = idempotency_key {
+                    for (i, msg) in messages.iter_mut().enumerate() {
+                        if msg.dedup_id.is_none() {
+                            msg.dedup_id = Some(format!("{}:{}", key, i));
+                        }
+                    }
+                }
+
+                if messages.iter().any(|msg| !msg.has_valid_attributes()) {
+                    let body = json!({
+                        "msg": "Attribute names must be header tokens and values cannot contain control characters"
+                    });
+
+                    let res = create_response(
+                        &state,
+                        StatusCode::BadRequest,
+                        Some((
+                            body.to_string().into_bytes(),
+                            mime::APPLICATION_JSON
+                        )),
+                    );
+
+                    return future::ok((state, res))
+                }
+
+                if messages.iter().any(|msg| msg.priority.unwrap_or(0) > MAXIMUM_PRIORITY) {
+                    let body = json!({
+                        "msg": format!("Priority must be between 0 and {}", MAXIMUM_PRIORITY)
+                    });
+
+                    let res = create_response(
+                        &state,
+                        StatusCode::BadRequest,
+                        Some((
+                            body.to_string().into_bytes(),
+                            mime::APPLICATION_JSON
+                        )),
+                    );
+
+                    return future::ok((state, res))
+                }
+
+                if messages.iter().any(|msg| msg.delay.unwrap_or(0) > MAXIMUM_DELAY) {
+                    let body = json!({
+                        "msg": format!("Delay must be between 0 and {} seconds", MAXIMUM_DELAY)
+                    });
+
+                    let res = create_response(
+                        &state,
+                        StatusCode::BadRequest,
+                        Some((
+                            body.to_string().into_bytes(),
+                            mime::APPLICATION_JSON
+                        )),
+                    );
+
+                    return future::ok((state, res))
+                }
+
+                let now = Utc::now();
+                let invalid_deliver_at = messages.iter().any(|msg| match msg.deliver_at {
+                    Some(ref deliver_at) => match DateTime::parse_from_rfc3339(deliver_at) {
+                        Ok(deliver_at) => msg.delay.is_some() || deliver_at.timestamp() - now.timestamp() > MAXIMUM_DELAY as i64,
+                        Err(_) => true
+                    },
+                    None => false
+                });
+                if invalid_deliver_at {
+                    let body = json!({
+                        "msg": format!("deliver_at must be an RFC3339 timestamp within {} seconds, and cannot be combined with delay", MAXIMUM_DELAY)
+                    });
+
+                    let res = create_response(
+                        &state,
+                        StatusCode::BadRequest,
+                        Some((
+                            body.to_string().into_bytes(),
+                            mime::APPLICATION_JSON
+                        )),
+                    );
+
+                    return future::ok((state, res))
+                }
+
+                if messages.iter().any(|msg| msg.payload().is_none()) {
+                    let body = json!({
+                        "msg": format!("Message body must be text or {} encoded", BASE64_ENCODING)
+                    });
+
+                    let res = create_response(
+                        &state,
+                        StatusCode::BadRequest,
+                        Some((
+                            body.to_string().into_bytes(),
+                            mime::APPLICATION_JSON
+                        )),
+                    );
+
+                    return future::ok((state, res))
+                }
+
                 let ids = {
                     let connection = {
                         let redis_pool = RedisPool::borrow_mut_from(&mut state);
@@ -112,21 +263,15 @@ pub fn push_messages(mut state: State) -> Box<HandlerFuture> {
                     };
                     let name: String = {
                         let path = QueuePathExtractor::borrow_from(&state);
-                        path.name.clone().unwrap()
-                    };
-
-                    let mut messages: Vec<Message> = {
-                        let body_content: Value = serde_json::from_slice(&valid_body.to_vec()).unwrap();
-                        serde_json::from_value(body_content["messages"].clone()).unwrap()
+                        path.queue_id()
                     };
 
                     let result: Vec<String> = match get_queue(&name, &connection) {
-                        Ok(q) => {
+                        Ok(_q) => {
                             messages
                                 .into_iter()
                                 .map(|msg| {
-                                    let m = Message::with_body(&msg.body);
-                                    let mid = post_message(q.name.clone().unwrap(), m, &*connection).unwrap();
+                                    let mid = post_message(name.clone(), msg, &*connection).unwrap();
                                     mid
                                 }).collect()
                         },
@@ -166,14 +311,9 @@ pub fn reserve_messages(mut state: State) -> Box<HandlerFuture> {
         .concat2()
         .then(|full_body| match full_body {
             Ok(valid_body) => {
-                let connection = {
-                    let redis_pool = RedisPool::borrow_mut_from(&mut state);
-                    let connection = redis_pool.conn().unwrap();
-                    connection
-                };
                 let name: String = {
                     let path = QueuePathExtractor::borrow_from(&state);
-                    path.name.clone().unwrap()
+                    path.queue_id()
                 };
 
                 let body_content = String::from_utf8(valid_body.to_vec()).unwrap();
@@ -182,35 +322,107 @@ pub fn reserve_messages(mut state: State) -> Box<HandlerFuture> {
                     reserve_params.delete = Some(false)
                 }
 
-                match ::mq::message::reserve_messages(&name, &reserve_params, &connection) {
-                    Ok(messages) => {
-                        let body = json!({
-                            "messages": messages
-                        });
+                if reserve_params.n < 1 || reserve_params.n > MAXIMUM_NUMBER_TO_RESERVE {
+                    let body = json!({
+                        "msg": format!("n must be between 1 and {}", MAXIMUM_NUMBER_TO_RESERVE)
+                    });
 
-                        let res = create_response(
-                            &state,
-                            StatusCode::Ok,
-                            Some((
-                                body.to_string().into_bytes(),
-                                mime::APPLICATION_JSON
-                            )),
-                        );
+                    let res = create_response(
+                        &state,
+                        StatusCode::BadRequest,
+                        Some((
+                            body.to_string().into_bytes(),
+                            mime::APPLICATION_JSON
+                        )),
+                    );
 
-                        return future::ok((state, res));
-                    },
-                    Err(_e) => {
-                        let res = create_response(&state, StatusCode::NotFound, None);
-                        return future::ok((state, res));
-                    }
+                    return Box::new(future::ok((state, res)));
                 }
+
+                let invalid_timeout = reserve_params.timeout
+                    .map_or(false, |timeout| timeout < 1 || timeout > MAXIMUM_MESSAGE_TIMEOUT);
+                if invalid_timeout {
+                    let body = json!({
+                        "msg": format!("Timeout must be between 1 and {} seconds", MAXIMUM_MESSAGE_TIMEOUT)
+                    });
+
+                    let res = create_response(
+                        &state,
+                        StatusCode::BadRequest,
+                        Some((
+                            body.to_string().into_bytes(),
+                            mime::APPLICATION_JSON
+                        )),
+                    );
+
+                    return Box::new(future::ok((state, res)));
+                }
+
+                let wait = match reserve_params.wait {
+                    Some(wait) => cmp::min(wait, MAXIMUM_WAIT),
+                    None => 0
+                };
+                let deadline = Instant::now() + Duration::from_secs(wait.into());
+
+                wait_for_messages(state, name, reserve_params, deadline)
             },
-            Err(e) => future::err((state, e.into_handler_error()))
+            Err(e) => Box::new(future::err((state, e.into_handler_error())))
         });
 
     Box::new(f)
 }
 
+/// Reserves messages, waiting until `deadline` for new ones when the queue is empty.
+/// The Redis connection goes back to the pool while the request is waiting.
+fn wait_for_messages(mut state: State, name: String, reserve_params: ReserveMessageParams, deadline: Instant) -> Box<HandlerFuture> {
+    // Registered before reserving, so a message pushed in between still wakes the request.
+    let arrival = {
+        let queue_notifier = QueueNotifier::borrow_from(&state);
+        queue_notifier.notifier.wait(&name)
+    };
+
+    let reserved = {
+        let connection = {
+            let redis_pool = RedisPool::borrow_mut_from(&mut state);
+            let connection = redis_pool.conn().unwrap();
+            connection
+        };
+
+        ::mq::message::reserve_messages(&name, &reserve_params, &connection)
+    };
+
+    match reserved {
+        Ok(messages) => {
+            if messages.is_empty() && Instant::now() < deadline {
+                let f = arrival
+                    .select2(Delay::new(deadline))
+                    .then(move |_| wait_for_messages(state, name, reserve_params, deadline));
+
+                return Box::new(f);
+            }
+
+            let body = json!({
+                "messages": messages
+            });
+
+            let res = create_response(
+                &state,
+                StatusCode::Ok,
+                Some((
+                    body.to_string().into_bytes(),
+                    mime::APPLICATION_JSON
+                )),
+            );
+
+            Box::new(future::ok((state, res)))
+        },
+        Err(_e) => {
+            let res = create_response(&state, StatusCode::NotFound, None);
+            Box::new(future::ok((state, res)))
+        }
+    }
+}
+
 pub fn list_queues(mut state: State) -> Box<HandlerFuture> {
         let f = Body::take_from(&mut state)
             .concat2()
@@ -222,7 +434,39 @@ pub fn list_queues(mut state: State) -> Box<HandlerFuture> {
                         connection
                     };
 
-                    match ::mq::queue::list_queues(&connection) {
+                    let project_id: String = {
+                        let path = QueuePathExtractor::borrow_from(&state);
+                        path.project_id.clone()
+                    };
+
+                    let params = {
+                        let query = ListQueuesQueryStringExtractor::borrow_from(&state);
+                        ListQueuesParams {
+                            per_page: query.per_page.unwrap_or(QUEUES_PER_PAGE),
+                            previous: query.previous.clone(),
+                            prefix: query.prefix.clone(),
+                            full: query.full.unwrap_or(false)
+                        }
+                    };
+
+                    if params.per_page < 1 || params.per_page > MAXIMUM_QUEUES_PER_PAGE {
+                        let body = json!({
+                            "msg": format!("per_page must be between 1 and {}", MAXIMUM_QUEUES_PER_PAGE)
+                        });
+
+                        let res = create_response(
+                            &state,
+                            StatusCode::BadRequest,
+                            Some((
+                                body.to_string().into_bytes(),
+                                mime::APPLICATION_JSON
+                            ))
+                        );
+
+                        return future::ok((state, res));
+                    }
+
+                    match ::mq::queue::list_queues(&project_id, &params, &connection) {
                         Ok(queues) => {
                             let body = json!({
                                 "queues": queues
@@ -265,7 +509,7 @@ pub fn delete_queue(mut state: State) -> Box<HandlerFuture> {
 
                     let name: String = {
                         let path = QueuePathExtractor::borrow_from(&state);
-                        path.name.clone().unwrap()
+                        path.queue_id()
                     };
 
                     match delete(name, &connection) {
@@ -314,13 +558,17 @@ pub fn push_messages_via_webhook(mut state: State) -> Box<HandlerFuture> {
                 };
                 let name: String = {
                     let path = QueuePathExtractor::borrow_from(&state);
-                    path.name.clone().unwrap()
+                    path.queue_id()
                 };
-                let body_content: Value = serde_json::from_slice(&valid_body.to_vec()).unwrap();
-                let message = Message::with_body(&body_content.to_string());
+                // The payload is stored as it was sent, along with its content type.
+                let content_type: Option<String> = {
+                    let headers: &Headers = Headers::borrow_from(&state);
+                    headers.get::<ContentType>().map(|content_type| content_type.to_string())
+                };
+                let message = Message::with_payload(&valid_body.to_vec(), content_type);
 
-                let q = get_queue(&name, &connection).unwrap();
-                let id = post_message(q.name.clone().unwrap(), message, &*connection).expect("Message put on queue.");
+                let _ = get_queue(&name, &connection).unwrap();
+                let id = post_message(name, message, &*connection).expect("Message put on queue.");
 
                 let body = json!({
                     "id": id,
@@ -357,7 +605,7 @@ pub fn get_queue_info(mut state: State) -> Box<HandlerFuture> {
 
                 let name: String = {
                     let path = QueuePathExtractor::borrow_from(&state);
-                    path.name.clone().unwrap()
+                    path.queue_id()
                 };
 
                 let (body, status_code) = match ::mq::queue::get_queue_info(name, &connection) {
@@ -406,7 +654,7 @@ pub fn update_subscribers(mut state: State) -> Box<HandlerFuture> {
                 };
                 let name: String = {
                     let path = QueuePathExtractor::borrow_from(&state);
-                    path.name.clone().unwrap()
+                    path.queue_id()
                 };
 
                 let mut subscribers: Vec<QueueSubscriber> = {
@@ -461,7 +709,7 @@ pub fn replace_subscribers(mut state: State) -> Box<HandlerFuture> {
                 };
                 let name: String = {
                     let path = QueuePathExtractor::borrow_from(&state);
-                    path.name.clone().unwrap()
+                    path.queue_id()
                 };
 
                 let mut subscribers: Vec<QueueSubscriber> = {
@@ -567,7 +815,7 @@ pub fn delete_subscribers(mut state: State) -> Box<HandlerFuture> {
                 };
                 let name: String = {
                     let path = QueuePathExtractor::borrow_from(&state);
-                    path.name.clone().unwrap()
+                    path.queue_id()
                 };
 
                 let mut subscribers: Vec<QueueSubscriber> = {
@@ -624,7 +872,7 @@ pub fn update_queue(mut state: State) -> Box<HandlerFuture> {
                 };
                 let (project_id, name) = {
                     let path = QueuePathExtractor::borrow_from(&state);
-                    (path.project_id.clone(), path.name.clone().unwrap())
+                    (path.project_id.clone(), path.queue_id())
                 };
 
                 let current_queue_info = ::mq::queue::get_queue_info(name.clone(), &connection);
@@ -698,7 +946,7 @@ pub fn update_queue(mut state: State) -> Box<HandlerFuture> {
                     "msg": error_message
                 });
 
-                let status_code = match error_message.contains("subscriber") {
+                let status_code = match error_message.contains("subscriber") || error_message.contains("cannot contain") {
                     true => StatusCode::BadRequest,
                     false => StatusCode::Forbidden
                 };
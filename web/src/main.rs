@@ -1,4 +1,5 @@
 extern crate futures;
+extern crate tokio;
 extern crate gotham;
 #[macro_use]
 extern crate gotham_derive;
@@ -21,8 +22,11 @@ extern crate scheduled_thread_pool;
 extern crate bcrypt;
 extern crate queue;
 extern crate chrono;
+extern crate base64;
 #[macro_use]
 extern crate failure;
+#[macro_use]
+extern crate lazy_static;
 
 mod middleware;
 mod pool;
@@ -32,7 +36,11 @@ mod user;
 mod auth;
 mod project;
 
-use std::env;
+use std::{
+    env,
+    process,
+    sync::Arc
+};
 
 use gotham::{
     router::{
@@ -48,18 +56,25 @@ use gotham::{
 use pool::*;
 use middleware::{
     auth::{AuthMiddleware},
-    redis::RedisMiddleware
+    redis::RedisMiddleware,
+    notifier::NotifierMiddleware
+};
+use mq::{
+    notifier::Notifier,
+    redrive::{redrive, RedriveOptions},
+    migration::migrate_project
 };
 
 use api::{
-    queue::QueuePathExtractor,
+    queue::{QueuePathExtractor, ListQueuesQueryStringExtractor},
     message::{
         QueryStringExtractor
     }
 };
 
-fn router(pool: Pool) -> Router {
+fn router(pool: Pool, notifier: Arc<Notifier>) -> Router {
     let redis_middleware = RedisMiddleware::with_pool(pool);
+    let notifier_middleware = NotifierMiddleware::with_notifier(notifier);
     let pipelines = new_pipeline_set();
     let (pipelines, default) = pipelines.add(
         new_pipeline()
@@ -70,6 +85,7 @@ fn router(pool: Pool) -> Router {
     let (pipelines, extended) = pipelines.add(
         new_pipeline()
             .add(redis_middleware.clone())
+            .add(notifier_middleware)
             // Disabled Auth
             // .add(AuthMiddleware)
             .build()
@@ -93,6 +109,7 @@ fn router(pool: Pool) -> Router {
             route.scope("/3/projects/:project_id", |route| {
                 route.get("/queues")
                     .with_path_extractor::<QueuePathExtractor>()
+                    .with_query_string_extractor::<ListQueuesQueryStringExtractor>()
                     .to(api::queue::list_queues);
                 route.scope("/queues/:name", |route| {
                     route.put("")
@@ -112,6 +129,11 @@ fn router(pool: Pool) -> Router {
                         .post("/reservations")
                         .with_path_extractor::<QueuePathExtractor>()
                         .to(api::queue::reserve_messages);
+                    route
+                        .get("/reservations")
+                        .with_path_extractor::<QueuePathExtractor>()
+                        .with_query_string_extractor::<QueryStringExtractor>()
+                        .to(api::message::list_reservations);
                     route.delete("")
                         .with_path_extractor::<QueuePathExtractor>()
                         .to(api::queue::delete_queue);
@@ -140,6 +162,16 @@ fn router(pool: Pool) -> Router {
                         .with_query_string_extractor::<QueryStringExtractor>()
                         .to(api::message::peek_messages);
 
+                    route.post("/messages/move")
+                        .with_path_extractor::<QueuePathExtractor>()
+                        .to(api::message::move_messages);
+                    route.post("/messages/copy")
+                        .with_path_extractor::<QueuePathExtractor>()
+                        .to(api::message::copy_messages);
+                    route.post("/messages/purge")
+                        .with_path_extractor::<QueuePathExtractor>()
+                        .to(api::message::purge_messages);
+
                     route.post("/messages/:message_id/release")
                         .with_path_extractor::<QueuePathExtractor>()
                         .to(api::message::release_message);
@@ -156,6 +188,10 @@ fn router(pool: Pool) -> Router {
                     route.get("/messages/:message_id/subscribers")
                         .with_path_extractor::<QueuePathExtractor>()
                         .to(api::message::get_push_statuses);
+
+                    route.get("/messages/:message_id/history")
+                        .with_path_extractor::<QueuePathExtractor>()
+                        .to(api::message::get_message_history);
                 });
             });
         });
@@ -166,9 +202,61 @@ pub fn main() {
     env_logger::init();
 
     let pool = new_pool();
+    let args: Vec<String> = env::args().collect();
+    if args.len() > 1 {
+        run_command(&args[1..], pool);
+        return;
+    }
+
+    let notifier = Arc::new(Notifier::new());
+    mq::scheduler::start(pool.clone());
+    mq::notifier::start(notifier.clone());
     let port: String = env::var("PORT").expect("$PORT is provided");
 
     let addr = format!("0.0.0.0:{}", port);
     info!("RustMQ web started on: {}", addr);
-    gotham::start(addr, router(pool))
+    gotham::start(addr, router(pool, notifier))
+}
+
+/// Runs a maintenance command instead of the server, e.g. `web redrive <project_id> <error_queue>`.
+fn run_command(args: &[String], pool: Pool) {
+    let connection = pool.get().expect("Redis connection is available");
+    match args[0].as_str() {
+        "redrive" => {
+            let options = match RedriveOptions::from_args(&args[1..]) {
+                Ok(options) => options,
+                Err(e) => {
+                    error!("Usage: web redrive <project_id> <error_queue> [--limit N] [--rate N] [--subscriber NAME] [--status CODE]: {}", e);
+                    process::exit(2);
+                }
+            };
+            match redrive(&options, &connection) {
+                Ok(report) => info!("Redrive from {:?}: {} redriven, {} skipped", options.error_queue, report.redriven, report.skipped),
+                Err(e) => {
+                    error!("Redrive from {:?} failed: {}", options.error_queue, e);
+                    process::exit(1);
+                }
+            }
+        },
+        "migrate-project" => {
+            let project_id = match args.get(1) {
+                Some(project_id) => project_id,
+                None => {
+                    error!("Usage: web migrate-project <project_id>");
+                    process::exit(2);
+                }
+            };
+            match migrate_project(project_id, &connection) {
+                Ok(report) => info!("Migration to project {:?}: {} migrated, {} skipped", project_id, report.migrated, report.skipped),
+                Err(e) => {
+                    error!("Migration to project {:?} failed: {}", project_id, e);
+                    process::exit(1);
+                }
+            }
+        },
+        command => {
+            error!("Unknown command {:?}", command);
+            process::exit(2);
+        }
+    }
 }
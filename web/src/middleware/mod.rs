@@ -1,2 +1,3 @@
 pub mod redis;
-pub mod auth;
\ No newline at end of file
+pub mod auth;
+pub mod notifier;
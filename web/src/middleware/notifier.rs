@@ -0,0 +1,52 @@
+use std::{
+    io,
+    sync::Arc
+};
+use gotham::{
+    handler::HandlerFuture,
+    middleware::{
+        Middleware,
+        NewMiddleware
+    },
+    state::State
+};
+use mq::notifier::Notifier;
+
+#[derive(StateData)]
+pub struct QueueNotifier {
+    pub notifier: Arc<Notifier>
+}
+
+#[derive(Clone)]
+pub struct NotifierMiddleware {
+    notifier: Arc<Notifier>
+}
+
+impl NotifierMiddleware {
+    pub fn with_notifier(notifier: Arc<Notifier>) -> Self {
+        NotifierMiddleware {
+            notifier
+        }
+    }
+}
+
+impl Middleware for NotifierMiddleware {
+    fn call<Chain>(self, mut state: State, chain: Chain) -> Box<HandlerFuture>
+    where
+        Chain: FnOnce(State) -> Box<HandlerFuture>,
+    {
+        state.put(QueueNotifier {
+            notifier: self.notifier
+        });
+
+        chain(state)
+    }
+}
+
+impl NewMiddleware for NotifierMiddleware {
+    type Instance = NotifierMiddleware;
+
+    fn new_middleware(&self) -> io::Result<Self::Instance> {
+        Ok(self.clone())
+    }
+}
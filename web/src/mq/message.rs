@@ -5,77 +5,169 @@ use objectid::{ObjectId};
 use redis::*;
 use serde_redis::RedisDeserialize;
 use api::message::MessageDeleteBodyRequest;
-use mq::queue::*;
+use mq::{
+    queue::*,
+    scripts
+};
 use queue::{
-    message::{Message, MessageState, PushMessage},
+    compression::{compress, decompress, GZIP_COMPRESSION},
+    message::{HistoryEvent, Message, MessageFilter, MessageState, PushMessage, BASE64_ENCODING},
     queue::Queue,
-    queue_info::{QueueInfo, QueueType, PushStatus}
+    queue_info::{QueueInfo, QueueType, PushStatus, MESSAGE_TIMEOUT, MESSAGE_EXPIRATION, DEDUP_WINDOW, COMPRESSION_THRESHOLD}
 };
 use failure::Error;
+use chrono::prelude::*;
+use base64::encode;
+use std::cmp;
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ReserveMessageParams {
     pub n: i32,
-    pub delete: Option<bool>
+    pub timeout: Option<u32>,
+    pub wait: Option<u32>,
+    pub delete: Option<bool>,
+    pub consumer_id: Option<String>
+}
+
+/// A reserved message and who holds it, as listed by `GET /reservations`.
+#[derive(Debug, Serialize)]
+pub struct Reservation {
+    pub id: String,
+    pub reservation_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")] pub consumer_id: Option<String>,
+    pub reserved_count: Option<u32>,
+    pub reserved_at: Option<String>,
+    pub reserved_until: Option<String>
 }
 
 pub const MAXIMUM_NUMBER_TO_PEEK: i32 = 1;
+pub const MAXIMUM_PEEK_SIZE: i32 = 100;
+pub const MAXIMUM_NUMBER_TO_RESERVE: i32 = 100;
+pub const MAXIMUM_NUMBER_TO_TRANSFER: usize = 100;
+const FIND_PAGE_SIZE: isize = 100;
+const PUSHED_INDEXED_FIELD: &str = "pushed_indexed";
+pub const MAXIMUM_WAIT: u32 = 30;
+pub const MAXIMUM_BATCH_SIZE: usize = 1000;
+pub const MAXIMUM_DELAY: u32 = 604800;
+
+/// Builds a message from its hash. Compressed bodies are decompressed, and binary bodies,
+/// stored as raw bytes, are base64 encoded so the message can be returned as JSON.
+/// Reservation times are stored as unix seconds and returned as RFC3339, like other times.
+pub fn message_from_value(v: Value) -> Result<Message, Error> {
+    let v = match v {
+        Value::Bulk(mut fields) => {
+            let body_field = Value::Data(b"body".to_vec());
+            let time_fields = [Value::Data(b"reserved_at".to_vec()), Value::Data(b"reserved_until".to_vec())];
+            let has_field = |fields: &Vec<Value>, name: &str, value: &str| fields
+                .chunks(2)
+                .any(|field| field.len() == 2 && field[0] == Value::Data(name.as_bytes().to_vec()) && field[1] == Value::Data(value.as_bytes().to_vec()));
+
+            let is_compressed = has_field(&fields, "compression", GZIP_COMPRESSION);
+            let is_binary = has_field(&fields, "encoding", BASE64_ENCODING);
+            let mut i = 0;
+            while i + 1 < fields.len() {
+                if fields[i] == body_field {
+                    if let Value::Data(ref mut body) = fields[i + 1] {
+                        if is_compressed {
+                            let decompressed = decompress(body.as_slice())?;
+                            *body = decompressed;
+                        }
+                        if is_binary {
+                            let encoded = encode(body.as_slice()).into_bytes();
+                            *body = encoded;
+                        }
+                    }
+                } else if time_fields.contains(&fields[i]) {
+                    if let Ok(timestamp) = from_redis_value::<i64>(&fields[i + 1]) {
+                        fields[i + 1] = Value::Data(Utc.timestamp(timestamp, 0).to_rfc3339().into_bytes());
+                    }
+                }
+                i += 2;
+            }
+
+            Value::Bulk(fields)
+        },
+        v => v
+    };
 
+    Ok(v.deserialize()?)
+}
+
+/// Puts a message on the queue and returns its id. A message repeating the `dedup_id`
+/// of a message pushed within the queue's dedup window is not enqueued again,
+/// the id of the original message is returned instead.
 pub fn push_message(queue_name: String, message: Message, con: &Connection) -> Result<String, Error> {
     let queue_key: String = Queue::get_queue_key(&queue_name);
+    let queue_info = get_queue_info(queue_name.clone(), con)?;
+    let dedup_window = queue_info.dedup_window.unwrap_or(DEDUP_WINDOW);
+    let compression_threshold = queue_info.compression_threshold.unwrap_or(COMPRESSION_THRESHOLD);
 
-    let mut queue_unreserved_key = String::new();
-    queue_unreserved_key.push_str(&queue_key.clone());
-    queue_unreserved_key.push_str(":unreserved:msg");
+    let payload = match message.payload() {
+        Some(payload) => payload,
+        None => bail!("Message body is not valid for its encoding")
+    };
+    let payload_size = payload.len();
+    // Large bodies are stored compressed, unless compression does not make them smaller.
+    let (payload, compression) = if compression_threshold > 0 && payload_size > compression_threshold as usize {
+        let compressed = compress(&payload)?;
+        if compressed.len() < payload_size {
+            (compressed, GZIP_COMPRESSION)
+        } else {
+            (payload, "")
+        }
+    } else {
+        (payload, "")
+    };
+    let priority = message.priority.unwrap_or(0);
+    let id = ObjectId::new().unwrap().to_string();
+    let now = Utc::now().timestamp();
+    // A message scheduled at an absolute time is delayed until then.
+    let delay = match message.deliver_at {
+        Some(ref deliver_at) => {
+            let deliver_at = DateTime::parse_from_rfc3339(deliver_at)?;
+            cmp::max(deliver_at.timestamp() - now, 0) as u32
+        },
+        None => message.delay.unwrap_or(0)
+    };
 
-    let msg_counter_key = get_message_counter_key(&queue_name);
-    let mut msg_key = String::new();
-    msg_key.push_str(&queue_key);
-    msg_key.push_str(":msg:");
+    let (msg_id, created): (String, i32) = scripts::push()
+        .key(&queue_key)
+        .arg(&id)
+        .arg(payload)
+        .arg(now)
+        .arg(delay)
+        .arg(priority)
+        .arg(message.group_id.clone().unwrap_or(String::new()))
+        .arg(if dedup_window > 0 { message.dedup_id.clone().unwrap_or(String::new()) } else { String::new() })
+        .arg(dedup_window)
+        .arg(match message.attributes {
+            Some(ref attributes) => serde_json::to_string(attributes)?,
+            None => String::new()
+        })
+        .arg(message.encoding.clone().unwrap_or(String::new()))
+        .arg(message.content_type.clone().unwrap_or(String::new()))
+        .arg(compression)
+        .arg(payload_size)
+        .invoke(con)?;
+
+    if created == 1 && delay == 0 {
+        let mut msg = message;
+        msg.id = Some(msg_id.clone());
+        msg.source_msg_id = Some(msg_id.clone());
+        msg.dedup_id = None;
+        msg.delay = None;
+        msg.deliver_at = None;
+        msg.state = Some(MessageState::Unreserved);
+        publish_message(&queue_name, msg, con)?;
+    }
 
-    let mut msg = Message::with_body(&message.body);
-    let msg_id = redis::transaction(con, &[&msg_counter_key], |pipe| {
-        let msg_id: i32 = con.get(&msg_counter_key)?;
-        let id = ObjectId::new().unwrap();
-        msg_key.push_str(&id.to_string());
-        msg.source_msg_id = Some(id.clone().to_string());
-        let _response = pipe
-                .atomic()
-                .cmd("HMSET")
-                    .arg(&msg_key)
-                    .arg("body")
-                    .arg(&message.body)
-                    .arg("id")
-                    .arg(&id.to_string())
-                    .arg("source_msg_id")
-                    .arg(&id.to_string())
-                    .arg("state")
-                    .arg(&msg.state.clone().unwrap().to_string())
-                .cmd("ZADD")
-                    .arg(&queue_unreserved_key)
-                    .arg(&msg_id.to_string())
-                    .arg(&msg_key)
-                    .ignore()
-                .cmd("INCR")
-                    .arg(&msg_counter_key)
-                    .ignore()
-                .cmd("HINCRBY")
-                    .arg(&queue_key)
-                    .arg("size")
-                    .arg(1)
-                    .ignore()
-                .cmd("HINCRBY")
-                    .arg(&queue_key)
-                    .arg("total_messages")
-                    .arg(1)
-                    .ignore()
-                .query(con)?;
-
-        Ok(Some(id.to_string()))
-    }).unwrap();
-
-    msg.id = Some(msg_id.clone());
-    let queue = get_queue(&queue_name, &con)?;
+    Ok(msg_id)
+}
+
+/// Sends the message to the pusher if the queue is a push queue.
+pub fn publish_message(queue_name: &String, msg: Message, con: &Connection) -> Result<(), Error> {
+    let queue_key: String = Queue::get_queue_key(queue_name);
+    let queue = get_queue(queue_name, &con)?;
     let qi_as_string = queue.value.expect("Queue Info should be present");
 
     let qi: QueueInfo = serde_json::from_str(qi_as_string.as_str())?;
@@ -95,128 +187,237 @@ pub fn push_message(queue_name: String, message: Message, con: &Connection) -> R
             .execute(con);
     }
 
-    Ok(msg_id)
+    Ok(())
 }
 
-pub fn get_message(queue_id: &String, message_id: &String, con: &Connection) -> Result<Message, Error> {
-    let queue_key = Queue::get_queue_key(queue_id);
-    let mut msg_key = String::new();
-    msg_key.push_str(&queue_key);
-    msg_key.push_str(":msg:");
-    msg_key.push_str(&message_id.to_string());
-    let v: Value = con.hgetall(msg_key)?;
+/// Moves delayed messages whose delay has passed to the unreserved set,
+/// so they can be reserved, and hands them to the pusher for push queues.
+pub fn promote_delayed_messages(queue_name: &String, con: &Connection) -> Result<usize, Error> {
+    let queue_key = Queue::get_queue_key(queue_name);
+    let now = Utc::now().timestamp();
+
+    let promoted: Vec<Value> = scripts::promote()
+        .key(&queue_key)
+        .arg(now)
+        .arg(MAXIMUM_BATCH_SIZE)
+        .invoke(con)?;
+
+    let promoted_count = promoted.len();
+    for v in promoted {
+        let msg: Message = message_from_value(v)?;
+        publish_message(queue_name, msg, con)?;
+    }
 
-    Ok(v.deserialize()?)
+    Ok(promoted_count)
 }
 
-pub fn delete_message(queue_name: &String, message: &Message, con: &Connection) -> Result<bool, Error> {
-    let mut queue_key = String::new();
-    queue_key.push_str("queue:");
-    queue_key.push_str(&queue_name);
+/// Returns messages whose reservation has timed out to the unreserved set,
+/// at the position they were reserved from.
+pub fn requeue_expired_reservations(queue_name: &String, con: &Connection) -> Result<usize, Error> {
+    let queue_key = Queue::get_queue_key(queue_name);
+    let now = Utc::now().timestamp();
 
-    let mut queue_unreserved_key = String::new();
-    queue_unreserved_key.push_str(&queue_key.clone());
-    queue_unreserved_key.push_str(":unreserved:msg");
+    let requeued: usize = scripts::requeue()
+        .key(&queue_key)
+        .arg(now)
+        .arg(MAXIMUM_BATCH_SIZE)
+        .invoke(con)?;
 
-    let mut queue_reserved_key = String::new();
-    queue_reserved_key.push_str(&queue_key.clone());
-    queue_reserved_key.push_str(":reserved:msg");
+    Ok(requeued)
+}
+
+/// Deletes messages that were pushed longer than the queue's `message_expiration` ago,
+/// whatever state they are in. Returns how many messages were expired.
+pub fn expire_messages(queue_name: &String, con: &Connection) -> Result<usize, Error> {
+    let queue_key = Queue::get_queue_key(queue_name);
+    index_pushed_messages(queue_name, con)?;
+
+    let queue_info = get_queue_info(queue_name.clone(), con)?;
+    let expiration = queue_info.message_expiration.unwrap_or(MESSAGE_EXPIRATION);
+    let pushed_before = Utc::now().timestamp() - expiration as i64;
+
+    let expired: usize = scripts::expire()
+        .key(&queue_key)
+        .arg(pushed_before)
+        .arg(MAXIMUM_BATCH_SIZE)
+        .arg(Utc::now().timestamp())
+        .invoke(con)?;
+
+    Ok(expired)
+}
+
+/// Push times are recorded for messages pushed since messages expire. Those of older
+/// messages are taken from the time in their ids, once per queue.
+fn index_pushed_messages(queue_name: &String, con: &Connection) -> Result<(), Error> {
+    let queue_key = Queue::get_queue_key(queue_name);
+    let indexed: bool = con.hexists(&queue_key, PUSHED_INDEXED_FIELD)?;
+    if indexed {
+        return Ok(());
+    }
+
+    let pushed_key = get_pushed_messages_key(queue_name);
+    let mut sets = vec![
+        get_unreserved_messages_key(queue_name),
+        get_reserved_messages_key(queue_name),
+        get_delayed_messages_key(queue_name)
+    ];
+    sets.extend(get_waiting_messages_keys(queue_name, con)?);
+    for set in sets {
+        // ZSCAN returns every message that stays in the set while it runs, members and scores alternating.
+        let iter: Iter<String> = cmd("ZSCAN").arg(&set).cursor_arg(0).iter(con)?;
+        let mut pipeline = pipe();
+        let mut pending = 0;
+        for msg_key in iter.step_by(2) {
+            if let Some(pushed_at) = pushed_at_from_msg_key(&msg_key) {
+                pipeline.cmd("ZADD").arg(&pushed_key).arg("NX").arg(pushed_at).arg(&msg_key).ignore();
+                pending += 1;
+            }
+            if pending == MAXIMUM_BATCH_SIZE {
+                let () = pipeline.query(con)?;
+                pipeline = pipe();
+                pending = 0;
+            }
+        }
+        if pending > 0 {
+            let () = pipeline.query(con)?;
+        }
+    }
+    let () = con.hset(&queue_key, PUSHED_INDEXED_FIELD, 1)?;
 
+    Ok(())
+}
+
+/// Message ids are ObjectIds, which start with the unix time they were created at in hex.
+fn pushed_at_from_msg_key(msg_key: &str) -> Option<i64> {
+    let id = msg_key.rsplit(':').next()?;
+    if id.len() != 24 {
+        return None;
+    }
+
+    id.get(..8).and_then(|seconds| i64::from_str_radix(seconds, 16).ok())
+}
+
+pub fn get_message(queue_id: &String, message_id: &String, con: &Connection) -> Result<Message, Error> {
+    let queue_key = Queue::get_queue_key(queue_id);
     let mut msg_key = String::new();
     msg_key.push_str(&queue_key);
     msg_key.push_str(":msg:");
-    msg_key.push_str(&message.id.clone().unwrap());
+    msg_key.push_str(&message_id.to_string());
+    let v: Value = con.hgetall(msg_key)?;
 
-    let deleted: Value = pipe()
-        .zrem(&queue_reserved_key, &[&msg_key]).ignore()
-        .zrem(&queue_unreserved_key, &[&msg_key]).ignore()
-        .del(&msg_key)
-        .query(con)?;
+    message_from_value(v)
+}
 
-    let mut status: Vec<u8> = from_redis_value(&deleted).unwrap();
+/// Outcome of deleting a single message.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeleteOutcome {
+    Deleted,
+    NotFound,
+    /// The message has been reserved, so it can only be deleted with its reservation id.
+    ReservationRequired,
+    /// The reservation id is not the current one, or the reservation has expired.
+    StaleReservation,
+    /// Deleting failed, the message may still be there.
+    Error
+}
 
-    if status.pop() == Some(1) {
-        con.hincr(&queue_key, "size", -1)?;
-    }
+/// Deletes the message. A message that has ever been reserved is deleted only if
+/// `message.reservation_id` is its current, unexpired reservation.
+pub fn delete_message(queue_name: &String, message: &Message, con: &Connection) -> Result<DeleteOutcome, Error> {
+    let queue_key = Queue::get_queue_key(queue_name);
+    let reservation_id = match message.reservation_id.clone() {
+        Some(reservation_id) => reservation_id,
+        None => String::new()
+    };
 
-    Ok(true)
+    let deleted: i32 = scripts::delete()
+        .key(&queue_key)
+        .arg(message.id.clone().unwrap())
+        .arg(reservation_id)
+        .arg(Utc::now().timestamp())
+        .invoke(con)?;
+
+    match deleted {
+        1 => Ok(DeleteOutcome::Deleted),
+        0 => Ok(DeleteOutcome::NotFound),
+        -1 => Ok(DeleteOutcome::ReservationRequired),
+        -2 => Ok(DeleteOutcome::StaleReservation),
+        _ => bail!("Unexpected delete result {}", deleted)
+    }
 }
 
 pub fn reserve_messages(queue_name: &String, reserve_params: &ReserveMessageParams, con: &Connection) -> Result<Vec<Message>, Error> {
     let mut result = Vec::new();
 
-    let mut queue_key = String::new();
-    queue_key.push_str("queue:");
-    queue_key.push_str(&queue_name);
+    let queue_key = Queue::get_queue_key(queue_name);
 
-    let mut queue_unreserved_key = String::new();
-    queue_unreserved_key.push_str(&queue_key.clone());
-    queue_unreserved_key.push_str(":unreserved:msg");
+    let mut queue_info = get_queue_info(queue_name.clone(), con)?;
+    let timeout = match reserve_params.timeout {
+        Some(timeout) => timeout,
+        None => queue_info.message_timeout.unwrap_or(MESSAGE_TIMEOUT)
+    };
+    let now = Utc::now().timestamp();
+    let reserved_until = now + timeout as i64;
 
-    let mut queue_reserved_key = String::new();
-    queue_reserved_key.push_str(&queue_key.clone());
-    queue_reserved_key.push_str(":reserved:msg");
+    let delete_reserved = match reserve_params.delete {
+        Some(true) => "1",
+        _ => "0"
+    };
+    // Delayed messages of push queues are promoted by the scheduler, which hands them to the pusher.
+    let promote_delayed = match queue_info.is_pull() {
+        Some(true) => "1",
+        _ => "0"
+    };
 
-    let unreserved_msg_key_list: Result<Vec<(String, isize)>, RedisError> = con.zrangebyscore_limit_withscores(&queue_unreserved_key, "0", "+inf", 0, reserve_params.n as isize);
-    let mut reserved_msg_list = Vec::new();
-    let mut unreserved_msg_list = Vec::new();
-    match unreserved_msg_key_list {
-        Ok(unreserved_msg_key_list) => {
-            for msg_key in unreserved_msg_key_list {
-                reserved_msg_list.push((msg_key.1.clone(), msg_key.0.clone()));
-                unreserved_msg_list.push(msg_key.0.clone());
-            };
-        },
-        Err(err) => println!("Error: {:?}", err),
-    }
+    let script = scripts::reserve();
+    let mut invocation = script.prepare_invoke();
+    invocation.key(&queue_key);
 
-    if reserved_msg_list.is_empty() {
-        return Ok(result)
+    let mut max_reservations = 0;
+    if queue_info.is_pull() == Some(true) {
+        if let (Some(max), Some(dead_letter_queue)) = (queue_info.max_reservations, queue_info.dead_letter_queue.clone()) {
+            max_reservations = max;
+            invocation.key(Queue::get_queue_key(&queue_info.project_queue_id(&dead_letter_queue)));
+        }
     }
 
-    // 1. TX unreserved -> reserved
-    let _r: Vec<isize> = redis::transaction(con, &[&queue_unreserved_key, &queue_reserved_key], |pipe| {
-        pipe
-            .atomic()
-            .zrem(&queue_unreserved_key.clone(), unreserved_msg_list.clone()).ignore()
-            .zadd_multiple(&queue_reserved_key.clone(), &reserved_msg_list)
-            .query(con)
-    }).unwrap();
-
-    // 2. Loop over reserved (per n in request)
-    for unreserved_msg_key in unreserved_msg_list.clone() {
-        let id: ObjectId = ObjectId::new().unwrap();
-        let _r: Vec<isize> = redis::transaction(con, &[unreserved_msg_key.clone()], |pipe| {
-    // 2.2. update msg --> TX (?)
-            pipe
-                .atomic()
-                .hset_nx(unreserved_msg_key.clone(), "reservation_id", id.to_string())
-                .hset(unreserved_msg_key.clone(), "state", MessageState::Reserved.to_string()).ignore()
-                .hincr(unreserved_msg_key.clone(), "reserved_count", 1).ignore()
-                .query(con)
-        }).unwrap();
-    };
-    // 3. collect updated msgs
-    for updated_msg_key in unreserved_msg_list.clone() {
-        let v: Value = con.hgetall(&updated_msg_key)?;
-        result.push(v.deserialize()?);
-    };
+    invocation
+        .arg(now)
+        .arg(reserved_until)
+        .arg(max_reservations)
+        .arg(delete_reserved)
+        .arg(reserve_params.consumer_id.clone().unwrap_or(String::new()))
+        .arg(MAXIMUM_BATCH_SIZE)
+        .arg(promote_delayed);
+    for _ in 0..reserve_params.n {
+        let reservation_id: ObjectId = ObjectId::new().unwrap();
+        invocation.arg(reservation_id.to_string());
+    }
 
-    if reserve_params.delete == Some(true) {
-        for message in result.clone() {
-            let _ = delete_message(&queue_name, &message, con)?;
-        }
+    let reserved: Vec<Value> = invocation.invoke(con)?;
+    for v in reserved {
+        result.push(message_from_value(v)?);
     }
 
     Ok(result)
 }
 
-pub fn delete(queue_name: String, message_id: String, con: &Connection) -> Result<bool, Error> {
+pub fn delete(queue_name: String, message_id: String, reservation_id: Option<String>, con: &Connection) -> Result<DeleteOutcome, Error> {
     let m = Message {
+        attributes: None,
         id: Some(message_id),
         body: String::new(),
+        consumer_id: None,
+        content_type: None,
+        dedup_id: None,
         delay: None,
-        reservation_id: None,
+        deliver_at: None,
+        encoding: None,
+        group_id: None,
+        priority: None,
+        reservation_id: reservation_id,
+        reserved_until: None,
         reserved_count: None,
         source_msg_id: None,
         state: None
@@ -225,73 +426,71 @@ pub fn delete(queue_name: String, message_id: String, con: &Connection) -> Resul
     delete_message(&queue_name, &m, con)
 }
 
-pub fn delete_messages(queue_name: String, messages: &Vec<MessageDeleteBodyRequest>, con: &Connection) -> Result<Vec<bool>, Error> {
-    Ok(messages
+/// Deletes every message on its own. A failure is the outcome of its message only.
+pub fn delete_messages(queue_name: String, messages: &Vec<MessageDeleteBodyRequest>, con: &Connection) -> Vec<DeleteOutcome> {
+    messages
         .into_iter()
         .map(|message| {
-            match delete(queue_name.to_string(), message.id.to_owned(), con) {
-                Ok(deleted) => deleted,
-                Err(_) => {
-                    false
-                }
-            }
+            delete(queue_name.to_string(), message.id.to_owned(), message.reservation_id.clone(), con)
+                .unwrap_or_else(|e| {
+                    error!("Queue {:?}: message {:?} not deleted: {:?}", queue_name, message.id, e.to_string());
+                    DeleteOutcome::Error
+                })
         })
-        .collect())
+        .collect()
 }
 
-pub fn touch_message(queue_id: &String, message_id: &String, reservation_id: &String, con: &Connection) -> Result<String, Error> {
-    let queue_key = Queue::get_queue_key(queue_id);
-    let mut msg_key = String::new();
-    msg_key.push_str(&queue_key);
-    msg_key.push_str(":msg:");
-    msg_key.push_str(&message_id.to_string());
+/// Outcome of touching a reserved message.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TouchOutcome {
+    /// The new reservation id and the unix time the reservation expires at.
+    Touched(String, i64),
+    NotFound,
+    /// The reservation has expired, though the message may not have been requeued yet.
+    StaleReservation
+}
 
-    let msg = get_message(&queue_id, &message_id, con)?;
+/// Renews the reservation of a message. The message stays reserved for `timeout`
+/// seconds from now, or for the queue's `message_timeout` when no timeout is given.
+/// Only a current reservation that has not expired can be renewed.
+pub fn touch_message(queue_id: &String, message_id: &String, reservation_id: &String, timeout: Option<u32>, con: &Connection) -> Result<TouchOutcome, Error> {
+    let queue_key = Queue::get_queue_key(queue_id);
+    let id: ObjectId = ObjectId::new().unwrap();
 
-    let current_reservation_id = match msg.reservation_id {
-        Some(reservation_id) => reservation_id,
-        None => String::new(),
+    let queue_info = get_queue_info(queue_id.clone(), con)?;
+    let timeout = match timeout {
+        Some(timeout) => timeout,
+        None => queue_info.message_timeout.unwrap_or(MESSAGE_TIMEOUT)
     };
-
-    if current_reservation_id != reservation_id.to_string() {
-        bail!("Touch message was failed");
+    let now = Utc::now().timestamp();
+    let reserved_until = now + timeout as i64;
+
+    let touched: Value = scripts::touch()
+        .key(&queue_key)
+        .arg(message_id)
+        .arg(reservation_id)
+        .arg(id.to_string())
+        .arg(reserved_until)
+        .arg(now)
+        .invoke(con)?;
+
+    match touched {
+        Value::Int(0) => Ok(TouchOutcome::NotFound),
+        Value::Int(-2) => Ok(TouchOutcome::StaleReservation),
+        v => {
+            let (reservation_id, reserved_until): (String, i64) = from_redis_value(&v)?;
+            Ok(TouchOutcome::Touched(reservation_id, reserved_until))
+        }
     }
-
-    let id: ObjectId = ObjectId::new().unwrap();
-    let _: isize = con.hset(msg_key, "reservation_id", id.to_string())?;
-
-    Ok(id.to_string())
 }
 
-pub fn peek_messages(queue_name: &String, number_to_peek: &i32, con: &Connection) -> Result<Vec<Message>, Error> {
+/// Lists messages without reserving them. `state` picks the set to look at, `None` walks
+/// the unreserved, reserved and delayed messages in that order. Listing continues after
+/// the `cursor` message when one is given.
+pub fn peek_messages(queue_name: &String, number_to_peek: &i32, cursor: Option<&String>, state: Option<MessageState>, con: &Connection) -> Result<Vec<Message>, Error> {
     let mut result = Vec::new();
 
-    let mut queue_key = String::new();
-    queue_key.push_str("queue:");
-    queue_key.push_str(&queue_name);
-
-    let mut queue_unreserved_key = String::new();
-    queue_unreserved_key.push_str(&queue_key.clone());
-    queue_unreserved_key.push_str(":unreserved:msg");
-
-    let unreserved_msg_key_list: Vec<(String, isize)> = con.zrangebyscore_limit_withscores(&queue_unreserved_key, "0", "+inf", 0, *number_to_peek as isize).unwrap();
-    let mut message_key_list = Vec::new();
-    for msg_key in unreserved_msg_key_list {
-        message_key_list.push(msg_key.0.clone());
-    };
-
-    for msg_key in message_key_list {
-        let v: Value = con.hgetall(&msg_key)?;
-        result.push(v.deserialize()?);
-    };
-
-    Ok(result)
-}
-
-pub fn release_message(queue_name: &String, message_id: &String, reservation_id: &String, con: &Connection) -> Result<bool, Error> {
-    let mut queue_key = String::new();
-    queue_key.push_str("queue:");
-    queue_key.push_str(&queue_name);
+    let queue_key = Queue::get_queue_key(queue_name);
 
     let mut queue_unreserved_key = String::new();
     queue_unreserved_key.push_str(&queue_key.clone());
@@ -301,43 +500,106 @@ pub fn release_message(queue_name: &String, message_id: &String, reservation_id:
     queue_reserved_key.push_str(&queue_key.clone());
     queue_reserved_key.push_str(":reserved:msg");
 
-    let mut msg_key = String::new();
-    msg_key.push_str(&queue_key);
-    msg_key.push_str(":msg:");
-    msg_key.push_str(&message_id.to_string());
+    let queue_delayed_key = get_delayed_messages_key(queue_name);
+
+    promote_delayed_messages(queue_name, con)?;
+    requeue_expired_reservations(queue_name, con)?;
+
+    // Unreserved messages of a group wait behind its head in a set of their own.
+    let mut unreserved_keys = vec![queue_unreserved_key];
+    unreserved_keys.extend(get_waiting_messages_keys(queue_name, con)?);
+    let sets = match state {
+        Some(MessageState::Unreserved) => unreserved_keys,
+        Some(MessageState::Reserved) => vec![queue_reserved_key],
+        Some(MessageState::Delayed) => vec![queue_delayed_key],
+        None => {
+            unreserved_keys.extend(vec![queue_reserved_key, queue_delayed_key]);
+            unreserved_keys
+        }
+    };
 
-    let msg = get_message(&queue_name, &message_id, con)?;
+    let (mut set_index, mut start): (usize, isize) = (0, 0);
+    if let Some(cursor) = cursor {
+        let mut cursor_key = String::new();
+        cursor_key.push_str(&queue_key.clone());
+        cursor_key.push_str(":msg:");
+        cursor_key.push_str(cursor);
+
+        let mut found = false;
+        for (i, set) in sets.iter().enumerate() {
+            let rank: Option<isize> = con.zrank(set, &cursor_key)?;
+            if let Some(rank) = rank {
+                set_index = i;
+                start = rank + 1;
+                found = true;
+                break;
+            }
+        }
+        ensure!(found, "Cursor not found");
+    }
 
-    let current_reservation_id = match msg.reservation_id {
-        Some(reservation_id) => reservation_id,
-        None => String::new(),
-    };
-    if current_reservation_id != reservation_id.to_string() {
-        return Ok(false)
+    let mut message_key_list: Vec<String> = Vec::new();
+    for set in &sets[set_index..] {
+        let remaining = *number_to_peek as isize - message_key_list.len() as isize;
+        if remaining <= 0 {
+            break;
+        }
+        let msg_keys: Vec<String> = con.zrange(set, start, start + remaining - 1)?;
+        message_key_list.extend(msg_keys);
+        start = 0;
     }
 
-    let msg_score: i32 = con.zscore(&queue_reserved_key, &msg_key)?;
+    for msg_key in message_key_list {
+        let v: Value = con.hgetall(&msg_key)?;
+        let mut msg = message_from_value(v)?;
+        // Delayed messages show when they become available.
+        if msg.state == Some(MessageState::Delayed) {
+            let due: Option<i64> = con.zscore(get_delayed_messages_key(queue_name), &msg_key)?;
+            msg.deliver_at = due.map(|due| Utc.timestamp(due, 0).to_rfc3339());
+        }
+        result.push(msg);
+    };
 
-    let mut pipe = pipe();
+    Ok(result)
+}
 
-    let res: i32 = pipe
-        .zrem(&queue_reserved_key, &msg_key).ignore()
-        .zadd(&queue_unreserved_key, &msg_key, msg_score).ignore()
-        .hset(&msg_key, "state", MessageState::Unreserved.to_string()).ignore()
-        .hdel(&msg_key, "reservation_id")
-        .query(con)?;
+/// Lists reserved messages, the ones whose reservation expires first come first.
+/// Pages continue after the message id given as `cursor`.
+pub fn list_reservations(queue_name: &String, number_to_list: &i32, cursor: Option<&String>, con: &Connection) -> Result<Vec<Reservation>, Error> {
+    let messages = peek_messages(queue_name, number_to_list, cursor, Some(MessageState::Reserved), con)?;
 
-    if res == 1 {
-        return Ok(true)
-    } else {
-        return Ok(false)
-    }
+    Ok(messages
+        .into_iter()
+        .map(|msg| Reservation {
+            id: msg.id.unwrap_or(String::new()),
+            reservation_id: msg.reservation_id,
+            consumer_id: msg.consumer_id,
+            reserved_count: msg.reserved_count,
+            reserved_at: msg.reserved_at,
+            reserved_until: msg.reserved_until
+        })
+        .collect())
+}
+
+/// Returns a reserved message to the queue. With a `delay` the message stays
+/// invisible for that many seconds before it can be reserved again.
+pub fn release_message(queue_name: &String, message_id: &String, reservation_id: &String, delay: u32, con: &Connection) -> Result<bool, Error> {
+    let queue_key = Queue::get_queue_key(queue_name);
+    let now = Utc::now().timestamp();
+
+    let released: i32 = scripts::release()
+        .key(&queue_key)
+        .arg(message_id)
+        .arg(reservation_id)
+        .arg(now)
+        .arg(delay)
+        .invoke(con)?;
+
+    Ok(released == 1)
 }
 
 pub fn clear_messages(queue_name: &String, con: &Connection) -> Result<bool, Error> {
-    let mut queue_key = String::new();
-    queue_key.push_str("queue:");
-    queue_key.push_str(&queue_name);
+    let queue_key = Queue::get_queue_key(queue_name);
 
     let mut queue_msg_counter_key = String::new();
     queue_msg_counter_key.push_str(&queue_key.clone());
@@ -361,20 +623,191 @@ pub fn clear_messages(queue_name: &String, con: &Connection) -> Result<bool, Err
         if queue_msg_counter_key == key {
             continue;
         }
-        let _: () = cmd("DEL").arg(key).query(con)?;
-        let _: () = con.hincr(&queue_key, "size", -1)?;
+        let deleted: i32 = cmd("DEL").arg(&key).query(con)?;
+        // Delivery statuses are stored next to messages, but are not counted in size.
+        if deleted == 1 && !key.contains(":delivery:") {
+            let _: () = con.hincr(&queue_key, "size", -1)?;
+        }
     }
 
     let _ : () = con.del(queue_unreserved_key)?;
     let _ : () = con.del(queue_reserved_key)?;
+    let _ : () = con.del(get_delayed_messages_key(queue_name))?;
+    let _ : () = con.del(get_pushed_messages_key(queue_name))?;
+    for group_key in get_waiting_messages_keys(queue_name, con)? {
+        let _ : () = con.del(group_key)?;
+    }
+    let _ : () = con.del(get_locked_groups_key(queue_name))?;
+    // Every compressed body is gone with its message.
+    let _ : () = con.hdel(&queue_key, vec!["compressed_bytes", "uncompressed_bytes"])?;
 
     Ok(true)
 }
 
+/// Returns the sorted sets holding the messages in the state the filter asks for.
+/// Messages waiting behind their group's head come first: purging a head moves the next
+/// message of its group into the unreserved set, where it is still looked at.
+fn get_filtered_messages_keys(queue_name: &String, filter: &MessageFilter, con: &Connection) -> Result<Vec<String>, Error> {
+    let queue_key = Queue::get_queue_key(queue_name);
+
+    let mut queue_unreserved_key = String::new();
+    queue_unreserved_key.push_str(&queue_key.clone());
+    queue_unreserved_key.push_str(":unreserved:msg");
+
+    let mut queue_reserved_key = String::new();
+    queue_reserved_key.push_str(&queue_key.clone());
+    queue_reserved_key.push_str(":reserved:msg");
+
+    let queue_delayed_key = get_delayed_messages_key(queue_name);
+
+    let mut keys = match filter.group_id {
+        Some(ref group_id) => vec![get_group_messages_key(queue_name, group_id)],
+        None => get_waiting_messages_keys(queue_name, con)?
+    };
+    match filter.state {
+        Some(MessageState::Unreserved) => keys.push(queue_unreserved_key),
+        Some(MessageState::Reserved) => keys = vec![queue_reserved_key],
+        Some(MessageState::Delayed) => keys = vec![queue_delayed_key],
+        None => keys.extend(vec![queue_unreserved_key, queue_delayed_key])
+    };
+
+    Ok(keys)
+}
+
+/// Finds up to `limit` messages matching the filter, in queue order.
+pub fn find_messages(queue_name: &String, filter: &MessageFilter, limit: usize, con: &Connection) -> Result<Vec<Message>, Error> {
+    let mut result = Vec::new();
+    for set in get_filtered_messages_keys(queue_name, filter, con)? {
+        let mut start: isize = 0;
+        while result.len() < limit {
+            let msg_keys: Vec<String> = con.zrange(&set, start, start + FIND_PAGE_SIZE - 1)?;
+            if msg_keys.is_empty() {
+                break;
+            }
+            for msg_key in &msg_keys {
+                let v: Value = con.hgetall(msg_key)?;
+                let msg = message_from_value(v)?;
+                if result.len() < limit && filter.matches(&msg) {
+                    result.push(msg);
+                }
+            }
+            start += FIND_PAGE_SIZE;
+        }
+    }
+
+    Ok(result)
+}
+
+/// Deletes the messages matching the filter, and pushed before `pushed_before` when it is given.
+/// The queue is worked through a page at a time, so purging a large queue does not block Redis.
+/// Returns the number of deleted messages.
+pub fn purge_messages(queue_name: &String, filter: &MessageFilter, pushed_before: Option<i64>, con: &Connection) -> Result<usize, Error> {
+    // The script checks the state again, a message may be reserved before its page is purged.
+    let states = match filter.state {
+        Some(ref state) => vec![state.clone()],
+        None => vec![MessageState::Unreserved, MessageState::Delayed]
+    };
+    let mut purged = 0;
+    for set in get_filtered_messages_keys(queue_name, filter, con)? {
+        let mut start: isize = 0;
+        loop {
+            let msg_keys: Vec<String> = con.zrange(&set, start, start + FIND_PAGE_SIZE - 1)?;
+            if msg_keys.is_empty() {
+                break;
+            }
+
+            let mut message_ids = Vec::new();
+            for msg_key in &msg_keys {
+                let v: Value = con.hgetall(msg_key)?;
+                let msg = message_from_value(v)?;
+                if filter.matches(&msg) {
+                    message_ids.push(msg.id.unwrap());
+                }
+            }
+
+            let deleted = remove_messages(queue_name, &message_ids, pushed_before, &states, con)?;
+            purged += deleted;
+            // Deleted messages no longer take up ranks, the next page starts after the kept ones.
+            start += (msg_keys.len() - deleted) as isize;
+        }
+    }
+
+    Ok(purged)
+}
+
+/// Deletes the given messages that are in one of `states`, without checking reservations.
+/// With `pushed_before` only messages pushed before then are deleted. Returns the number of deleted messages.
+pub fn remove_messages(queue_name: &String, message_ids: &Vec<String>, pushed_before: Option<i64>, states: &[MessageState], con: &Connection) -> Result<usize, Error> {
+    if message_ids.is_empty() {
+        return Ok(0);
+    }
+
+    let script = scripts::purge();
+    let mut invocation = script.prepare_invoke();
+    invocation
+        .key(Queue::get_queue_key(queue_name))
+        .arg(Utc::now().timestamp())
+        .arg(match pushed_before {
+            Some(pushed_before) => pushed_before.to_string(),
+            None => String::from("+inf")
+        })
+        .arg(states.iter().map(|state| state.to_string()).collect::<Vec<String>>().join(" "));
+    for message_id in message_ids {
+        invocation.arg(message_id);
+    }
+
+    Ok(invocation.invoke(con)?)
+}
+
+/// Moves, or copies, messages to the destination queue in one step. Messages get new ids
+/// there and keep their `source_msg_id`. Reserved and missing messages are skipped.
+/// Returns the ids of the transferred messages with their new ids.
+pub fn transfer_messages(queue_name: &String, destination: &String, message_ids: &Vec<String>, copy: bool, con: &Connection) -> Result<Vec<(String, String)>, Error> {
+    ensure!(queue_name != destination, "Destination must be another queue");
+    get_queue_info(destination.clone(), con)?;
+
+    let script = scripts::transfer();
+    let mut invocation = script.prepare_invoke();
+    invocation
+        .key(Queue::get_queue_key(queue_name))
+        .key(Queue::get_queue_key(destination))
+        .arg(Utc::now().timestamp())
+        .arg(if copy { "1" } else { "0" });
+    for message_id in message_ids {
+        invocation
+            .arg(message_id)
+            .arg(ObjectId::new().unwrap().to_string());
+    }
+
+    let transferred: Vec<String> = invocation.invoke(con)?;
+    let mut result = Vec::new();
+    for ids in transferred.chunks(2) {
+        let new_id = ids[1].clone();
+        let msg = get_message(destination, &new_id, con)?;
+        if msg.state == Some(MessageState::Unreserved) {
+            publish_message(destination, msg, con)?;
+        }
+        result.push((ids[0].clone(), new_id));
+    }
+
+    Ok(result)
+}
+
+/// Returns the recorded state transitions of a message, oldest first.
+pub fn get_message_history(queue_name: &String, message_id: &String, con: &Connection) -> Result<Vec<HistoryEvent>, Error> {
+    let history_key = Queue::get_message_history_key(queue_name, message_id);
+    let events: Vec<String> = con.lrange(history_key, 0, -1)?;
+
+    let mut result = Vec::new();
+    for event in events {
+        result.push(serde_json::from_str(&event)?);
+    }
+
+    Ok(result)
+}
+
 pub fn get_push_statuses(queue_name: &String, message_id: &String, con: &Connection) -> Result<Vec<PushStatus>, Error> {
-    let mut scan_key = String::new();
-    scan_key.push_str("queue:");
-    scan_key.push_str(queue_name);
+    let mut scan_key = Queue::get_queue_key(queue_name);
     scan_key.push_str(":msg:");
     scan_key.push_str(message_id);
     scan_key.push_str(":delivery:*");
@@ -390,3 +823,68 @@ pub fn get_push_statuses(queue_name: &String, message_id: &String, con: &Connect
 
     Ok(result)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pushed_at_is_read_from_message_id() {
+        assert_eq!(Some(1537171099), pushed_at_from_msg_key("project:p:queue:q:msg:5b9f5e9b2f8a3c0001a1b2c3"));
+        assert_eq!(None, pushed_at_from_msg_key("project:p:queue:q:msg:not-an-object-id"));
+        assert_eq!(None, pushed_at_from_msg_key("project:p:queue:q:msg:zzzzzzzz2f8a3c0001a1b2c3"));
+    }
+
+    fn message_hash(fields: &[(&str, &[u8])]) -> Value {
+        Value::Bulk(fields
+            .iter()
+            .flat_map(|&(name, value)| vec![Value::Data(name.as_bytes().to_vec()), Value::Data(value.to_vec())])
+            .collect())
+    }
+
+    #[test]
+    fn compressed_text_is_decompressed() {
+        let compressed = compress(b"hello").unwrap();
+        let v = message_hash(&[("body", &compressed), ("compression", b"gzip")]);
+        let msg = message_from_value(v).unwrap();
+
+        assert_eq!("hello", msg.body);
+        assert!(!msg.is_binary());
+    }
+
+    #[test]
+    fn compressed_binary_is_decompressed_and_base64_encoded() {
+        let compressed = compress(&[0, 159, 146, 150]).unwrap();
+        let v = message_hash(&[("body", &compressed), ("compression", b"gzip"), ("encoding", b"base64")]);
+        let msg = message_from_value(v).unwrap();
+
+        assert_eq!("AJ+Slg==", msg.body);
+        assert_eq!(vec![0, 159, 146, 150], msg.payload().unwrap());
+    }
+
+    #[test]
+    fn binary_that_is_not_compressed_is_base64_encoded() {
+        let v = message_hash(&[("body", &[0, 159, 146, 150]), ("encoding", b"base64")]);
+        let msg = message_from_value(v).unwrap();
+
+        assert_eq!("AJ+Slg==", msg.body);
+    }
+
+    #[test]
+    fn body_without_compression_field_is_kept() {
+        let v = message_hash(&[("body", b"{\"a\": 1}"), ("id", b"5b9f5e9b2f8a3c0001a1b2c3")]);
+        let msg = message_from_value(v).unwrap();
+
+        assert_eq!("{\"a\": 1}", msg.body);
+        assert_eq!(Some(String::from("5b9f5e9b2f8a3c0001a1b2c3")), msg.id);
+    }
+
+    #[test]
+    fn reservation_times_are_returned_as_rfc3339() {
+        let v = message_hash(&[("body", b"b"), ("reserved_at", b"1537171099"), ("reserved_until", b"1537171159")]);
+        let msg = message_from_value(v).unwrap();
+
+        assert_eq!(Some(String::from("2018-09-17T07:58:19+00:00")), msg.reserved_at);
+        assert_eq!(Some(String::from("2018-09-17T07:59:19+00:00")), msg.reserved_until);
+    }
+}
@@ -0,0 +1,160 @@
+//! Moves queues created before projects into a project.
+//!
+//! Sorted sets, group sets and the locked groups hash hold full message keys, so their members are
+//! rewritten. Every other key of the queue is renamed. Run it while web and pusher are stopped.
+
+use std::collections::HashMap;
+use redis::{Commands, Connection, Iter, cmd};
+use serde_json;
+use failure::Error;
+use mq::queue::{get_queue_info, SCHEDULED_QUEUES_KEY};
+use queue::queue::{Queue, DEFAULT_QUEUE_KEY};
+
+const MEMBER_KEY_SUFFIXES: [&'static str; 4] = [
+    ":unreserved:msg",
+    ":reserved:msg",
+    ":delayed:msg",
+    ":pushed:msg"
+];
+const LOCKED_GROUPS_SUFFIX: &'static str = ":locked:groups";
+const GROUP_KEY_PREFIX: &'static str = ":group:";
+
+#[derive(Debug, Default)]
+pub struct MigrationReport {
+    pub migrated: usize,
+    pub skipped: usize
+}
+
+/// Moves every queue without a project into the project. Queues whose name is taken
+/// in the project already are skipped.
+pub fn migrate_project(project_id: &String, con: &Connection) -> Result<MigrationReport, Error> {
+    let mut report = MigrationReport::default();
+    let queue_ids: Vec<String> = con.smembers("queues")?;
+    let mut legacy_queue_keys = Vec::new();
+    for queue_id in &queue_ids {
+        let mut key = String::from(DEFAULT_QUEUE_KEY);
+        key.push_str(queue_id);
+        let exists: bool = con.exists(&key)?;
+        if exists {
+            legacy_queue_keys.push(key);
+        }
+    }
+
+    for queue_id in queue_ids {
+        if queue_id.contains(':') {
+            // Ids of project queues have it too, but only a queue without a project has this key.
+            let mut key = String::from(DEFAULT_QUEUE_KEY);
+            key.push_str(&queue_id);
+            if legacy_queue_keys.contains(&key) {
+                warn!("Queue {:?} not migrated: names cannot contain ':'", queue_id);
+                report.skipped += 1;
+            }
+            continue;
+        }
+
+        let mut queue_info = match get_queue_info(queue_id.clone(), con) {
+            Ok(queue_info) => queue_info,
+            Err(e) => {
+                warn!("Queue {:?} not migrated: {:?}", queue_id, e.to_string());
+                report.skipped += 1;
+                continue;
+            }
+        };
+        if queue_info.project_id.is_some() {
+            continue;
+        }
+
+        queue_info.project_id = Some(project_id.clone());
+        let new_queue_id = queue_info.queue_id();
+        let exists: bool = con.exists(Queue::get_queue_key(&new_queue_id))?;
+        if exists {
+            warn!("Queue {:?} not migrated: {:?} exists already", queue_id, new_queue_id);
+            report.skipped += 1;
+            continue;
+        }
+
+        migrate_queue(&queue_id, &new_queue_id, &legacy_queue_keys, con)?;
+        // The stored settings carry the project id from now on.
+        let () = con.hset(Queue::get_queue_key(&new_queue_id), "value", serde_json::to_string(&queue_info)?)?;
+        let () = con.srem("queues", &queue_id)?;
+        let () = con.sadd("queues", &new_queue_id)?;
+        let scheduled_at: Option<String> = con.zscore(SCHEDULED_QUEUES_KEY, &queue_id)?;
+        if let Some(scheduled_at) = scheduled_at {
+            let () = con.zrem(SCHEDULED_QUEUES_KEY, &queue_id)?;
+            let () = con.zadd(SCHEDULED_QUEUES_KEY, &new_queue_id, scheduled_at)?;
+        }
+        let () = con.zadd(Queue::get_project_queues_key(project_id), queue_info.name.clone().unwrap(), 0)?;
+        info!("Queue {:?} migrated to {:?}", queue_id, new_queue_id);
+        report.migrated += 1;
+    }
+
+    Ok(report)
+}
+
+fn migrate_queue(queue_id: &String, new_queue_id: &String, legacy_queue_keys: &Vec<String>, con: &Connection) -> Result<(), Error> {
+    let queue_key = Queue::get_queue_key(queue_id);
+    let new_queue_key = Queue::get_queue_key(new_queue_id);
+    let rename = |key: &String| -> String {
+        let mut new_key = new_queue_key.clone();
+        new_key.push_str(&key[queue_key.len()..]);
+
+        new_key
+    };
+
+    for suffix in MEMBER_KEY_SUFFIXES.iter() {
+        let mut key = queue_key.clone();
+        key.push_str(suffix);
+        // Scores are passed on as strings, so large ones keep their precision.
+        let members: Vec<(String, String)> = cmd("ZRANGE").arg(&key).arg(0).arg(-1).arg("WITHSCORES").query(con)?;
+        for (member, score) in members {
+            let () = cmd("ZADD").arg(rename(&key)).arg(score).arg(rename(&member)).query(con)?;
+        }
+        let () = con.del(&key)?;
+    }
+
+    let mut locked_groups_key = queue_key.clone();
+    locked_groups_key.push_str(LOCKED_GROUPS_SUFFIX);
+    let locked_groups: HashMap<String, String> = con.hgetall(&locked_groups_key)?;
+    for (group_id, msg_key) in locked_groups {
+        // Messages waiting behind the head of the group are members of a set of their own.
+        let mut group_key = queue_key.clone();
+        group_key.push_str(GROUP_KEY_PREFIX);
+        group_key.push_str(&group_id);
+        group_key.push_str(":msg");
+        let members: Vec<(String, String)> = cmd("ZRANGE").arg(&group_key).arg(0).arg(-1).arg("WITHSCORES").query(con)?;
+        for (member, score) in members {
+            let () = cmd("ZADD").arg(rename(&group_key)).arg(score).arg(rename(&member)).query(con)?;
+        }
+        let () = con.del(&group_key)?;
+        let () = con.hset(rename(&locked_groups_key), group_id, rename(&msg_key))?;
+    }
+    let () = con.del(&locked_groups_key)?;
+
+    let mut match_queue_key = queue_key.clone();
+    match_queue_key.push_str(":*");
+    // Queues without a project could be named `<name>:<more>`, their keys match as well.
+    let nested_queue_keys: Vec<String> = legacy_queue_keys.iter()
+        .filter(|key| key.starts_with(&match_queue_key[..match_queue_key.len() - 1]))
+        .map(|key| {
+            let mut nested_queue_key = key.clone();
+            nested_queue_key.push_str(":");
+
+            nested_queue_key
+        })
+        .collect();
+    let keys: Vec<String> = {
+        let iter: Iter<String> = cmd("SCAN").cursor_arg(0).arg("MATCH").arg(match_queue_key).iter(con)?;
+        iter.collect()
+    };
+    for key in keys {
+        let nested = nested_queue_keys.iter()
+            .any(|nested_queue_key| key.starts_with(nested_queue_key) || key == nested_queue_key[..nested_queue_key.len() - 1]);
+        if nested {
+            continue;
+        }
+        let () = con.rename(&key, rename(&key))?;
+    }
+    let () = con.rename(&queue_key, &new_queue_key)?;
+
+    Ok(())
+}
@@ -1,2 +1,7 @@
 pub mod queue;
 pub mod message;
+pub mod scripts;
+pub mod scheduler;
+pub mod notifier;
+pub mod redrive;
+pub mod migration;
@@ -0,0 +1,132 @@
+use std::{
+    collections::HashMap,
+    env,
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration
+};
+use futures::sync::oneshot;
+use redis::Client;
+use failure::Error;
+use queue::queue::Queue;
+
+/// Only queues of a project can be waited on through the API.
+const NOTIFY_CHANNEL_PATTERN: &'static str = "project:*:queue:*:msg:notify";
+const NOTIFY_CHANNEL_SUFFIX: &'static str = ":msg:notify";
+const RECONNECT_DELAY: u64 = 1;
+
+/// Keeps track of requests waiting for messages to arrive on a queue.
+pub struct Notifier {
+    waiters: Mutex<HashMap<String, Vec<oneshot::Sender<()>>>>
+}
+
+impl Notifier {
+    pub fn new() -> Notifier {
+        Notifier {
+            waiters: Mutex::new(HashMap::new())
+        }
+    }
+
+    /// Registers a waiter for the queue. The receiver completes on the next notification.
+    pub fn wait(&self, queue_name: &String) -> oneshot::Receiver<()> {
+        let (sender, receiver) = oneshot::channel();
+        let mut waiters = self.waiters.lock().unwrap();
+        let queue_waiters = waiters.entry(queue_name.clone()).or_insert_with(Vec::new);
+        queue_waiters.retain(|waiter| !waiter.is_canceled());
+        queue_waiters.push(sender);
+
+        receiver
+    }
+
+    pub fn notify(&self, queue_name: &String) {
+        let queue_waiters = {
+            let mut waiters = self.waiters.lock().unwrap();
+            waiters.remove(queue_name)
+        };
+
+        if let Some(queue_waiters) = queue_waiters {
+            for waiter in queue_waiters {
+                let _ = waiter.send(());
+            }
+        }
+    }
+}
+
+/// Listens for message notifications published by any web instance and wakes
+/// local waiters. A single connection outside of the pool is used for all queues.
+pub fn start(notifier: Arc<Notifier>) {
+    let builder = thread::Builder::new().name("notifier".to_string());
+    builder.spawn(move || {
+        info!("Notifier started");
+        loop {
+            if let Err(e) = listen(&notifier) {
+                error!("Notifier failed: {:?}", e.to_string());
+            }
+
+            thread::sleep(Duration::from_secs(RECONNECT_DELAY));
+        }
+    }).expect("Notifier thread is started");
+}
+
+fn listen(notifier: &Arc<Notifier>) -> Result<(), Error> {
+    let database_url = env::var("REDISCLOUD_URL").expect("$REDISCLOUD_URL is provided");
+    let client = Client::open(database_url.as_str())?;
+    let mut connection = client.get_connection()?;
+    let mut pubsub = connection.as_pubsub();
+    let () = pubsub.psubscribe(NOTIFY_CHANNEL_PATTERN)?;
+
+    loop {
+        let msg = pubsub.get_message()?;
+        if let Some(queue_name) = queue_name_from_channel(msg.get_channel_name()) {
+            notifier.notify(&queue_name);
+        }
+    }
+}
+
+/// Returns the id of the queue a `<queue key>:msg:notify` channel is for.
+/// Exactly one suffix is removed, the rest has to be a queue key.
+fn queue_name_from_channel(channel: &str) -> Option<String> {
+    if !channel.ends_with(NOTIFY_CHANNEL_SUFFIX) {
+        return None;
+    }
+
+    Queue::get_queue_id_from_key(&channel[..channel.len() - NOTIFY_CHANNEL_SUFFIX.len()])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::Future;
+
+    #[test]
+    fn notify_completes_waiting_receivers() {
+        let notifier = Notifier::new();
+        let queue_name = String::from("p1:emails");
+        let receiver = notifier.wait(&queue_name);
+
+        notifier.notify(&queue_name);
+
+        assert!(receiver.wait().is_ok());
+        assert!(notifier.waiters.lock().unwrap().get(&queue_name).is_none());
+    }
+
+    #[test]
+    fn cancelled_waiters_are_cleared() {
+        let notifier = Notifier::new();
+        let queue_name = String::from("p1:emails");
+        drop(notifier.wait(&queue_name));
+        drop(notifier.wait(&queue_name));
+
+        let _receiver = notifier.wait(&queue_name);
+
+        assert_eq!(1, notifier.waiters.lock().unwrap()[&queue_name].len());
+    }
+
+    #[test]
+    fn queue_name_is_taken_from_channel() {
+        assert_eq!(Some(String::from("p1:emails")), queue_name_from_channel("project:p1:queue:emails:msg:notify"));
+        assert_eq!(Some(String::from("p1:x:msg:notify")), queue_name_from_channel("project:p1:queue:x:msg:notify:msg:notify"));
+        assert_eq!(None, queue_name_from_channel("project:p1:queue:msg:notify"));
+        assert_eq!(None, queue_name_from_channel("project:p1:queue:emails:msg:channel"));
+    }
+}
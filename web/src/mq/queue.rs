@@ -19,19 +19,77 @@ use queue::{
 use std::collections::HashMap;
 use failure::Error;
 
-pub fn list_queues(con: &Connection) -> Result<Vec<QueueLite>, Error> {
-    let r: Vec<String> = con.smembers("queues")?;
+pub const QUEUES_PER_PAGE: usize = 30;
+pub const MAXIMUM_QUEUES_PER_PAGE: usize = 100;
+/// Queues with delayed or reserved messages, scored by when the scheduler should visit them next.
+pub const SCHEDULED_QUEUES_KEY: &str = "scheduled:queues";
+
+#[derive(Debug)]
+pub struct ListQueuesParams {
+    pub per_page: usize,
+    pub previous: Option<String>,
+    pub prefix: Option<String>,
+    pub full: bool
+}
+
+/// Lists the project's queues alphabetically, a page at a time. Pages continue after the
+/// `previous` queue name. With `full` the size, type and total messages of every queue are included.
+pub fn list_queues(project_id: &String, params: &ListQueuesParams, con: &Connection) -> Result<Vec<QueueLite>, Error> {
+    let (min, max) = list_queues_bounds(params);
+    let r: Vec<String> = cmd("ZRANGEBYLEX")
+        .arg(Queue::get_project_queues_key(project_id))
+        .arg(min)
+        .arg(max)
+        .arg("LIMIT")
+        .arg(0)
+        .arg(params.per_page)
+        .query(con)?;
 
     let mut res: Vec<QueueLite> = Vec::new();
     for queue_name in r {
-        res.push(QueueLite {
-            name: queue_name
-        })
+        let mut queue = QueueLite::new(queue_name);
+        if params.full {
+            let queue_info = get_queue_info(Queue::get_queue_id(project_id, &queue.name), con)?;
+            queue.size = queue_info.size;
+            queue.total_messages = queue_info.total_messages;
+            queue.queue_type = queue_info.queue_type;
+        }
+        res.push(queue)
     }
 
     Ok(res)
 }
 
+/// Returns the ZRANGEBYLEX range of the page: names starting with the prefix, after `previous`.
+fn list_queues_bounds(params: &ListQueuesParams) -> (Vec<u8>, Vec<u8>) {
+    let mut min: Vec<u8> = match params.prefix {
+        Some(ref prefix) => {
+            let mut min = vec![b'['];
+            min.extend(prefix.as_bytes());
+            min
+        },
+        None => vec![b'-']
+    };
+    if let Some(ref previous) = params.previous {
+        if params.prefix.as_ref().map_or(true, |prefix| previous >= prefix) {
+            min = vec![b'('];
+            min.extend(previous.as_bytes());
+        }
+    }
+    // No byte of a UTF-8 name is 0xff, so it sorts after every name starting with the prefix.
+    let max: Vec<u8> = match params.prefix {
+        Some(ref prefix) => {
+            let mut max = vec![b'['];
+            max.extend(prefix.as_bytes());
+            max.push(0xff);
+            max
+        },
+        None => vec![b'+']
+    };
+
+    (min, max)
+}
+
 pub fn get_queue(queue_name: &String, con: &Connection) -> Result<Queue, Error> {
     ensure!(!queue_name.trim().is_empty(), "Queue not found");
 
@@ -41,27 +99,89 @@ pub fn get_queue(queue_name: &String, con: &Connection) -> Result<Queue, Error>
     Ok(v.deserialize()?)
 }
 
-pub fn get_message_counter_key(queue_id: &String) -> String {
+pub fn get_unreserved_messages_key(queue_id: &String) -> String {
+    let mut key = String::new();
+    let queue_key = Queue::get_queue_key(queue_id);
+    key.push_str(&queue_key);
+    key.push_str(":unreserved:msg");
+
+    key
+}
+
+pub fn get_reserved_messages_key(queue_id: &String) -> String {
+    let mut key = String::new();
+    let queue_key = Queue::get_queue_key(queue_id);
+    key.push_str(&queue_key);
+    key.push_str(":reserved:msg");
+
+    key
+}
+
+pub fn get_pushed_messages_key(queue_id: &String) -> String {
+    let mut key = String::new();
+    let queue_key = Queue::get_queue_key(queue_id);
+    key.push_str(&queue_key);
+    key.push_str(":pushed:msg");
+
+    key
+}
+
+pub fn get_delayed_messages_key(queue_id: &String) -> String {
+    let mut key = String::new();
+    let queue_key = Queue::get_queue_key(queue_id);
+    key.push_str(&queue_key);
+    key.push_str(":delayed:msg");
+
+    key
+}
+
+pub fn get_locked_groups_key(queue_id: &String) -> String {
+    let mut key = String::new();
+    let queue_key = Queue::get_queue_key(queue_id);
+    key.push_str(&queue_key);
+    key.push_str(":locked:groups");
+
+    key
+}
+
+pub fn get_group_messages_key(queue_id: &String, group_id: &String) -> String {
     let mut key = String::new();
     let queue_key = Queue::get_queue_key(queue_id);
     key.push_str(&queue_key);
-    key.push_str(":msg:counter");
+    key.push_str(":group:");
+    key.push_str(group_id);
+    key.push_str(":msg");
 
     key
 }
 
+/// Sets of the available messages waiting behind the head of their group, ordered by group.
+pub fn get_waiting_messages_keys(queue_id: &String, con: &Connection) -> Result<Vec<String>, Error> {
+    let mut group_ids: Vec<String> = con.hkeys(get_locked_groups_key(queue_id))?;
+    group_ids.sort();
+
+    Ok(group_ids
+        .iter()
+        .map(|group_id| get_group_messages_key(queue_id, group_id))
+        .collect())
+}
+
 pub fn post_message(queue_name: String, message: Message, con: &Connection) -> Result<String, Error> {
     Ok(push_message(queue_name, message, con)?)
 }
 
+/// Creates the queue in the project of `queue_info`, along with its error and dead-letter queues.
 pub fn create_queue(queue_info: QueueInfo, con: &Connection) -> QueueInfo {
-    let mut queue_key = String::new();
-    queue_key.push_str("queue:");
+    let queue_id = queue_info.queue_id();
+    let mut queue_key = Queue::get_queue_key(&queue_id);
     let queue_name = queue_info.name.clone().unwrap();
-    queue_key.push_str(&queue_name);
     let now: DateTime<Utc> = Utc::now();
     let mut pipe = pipe();
-    pipe.cmd("SADD").arg("queues".to_string()).arg(&queue_name).ignore();
+    // The scheduler goes through all queues, listings only through the project's ones.
+    pipe.cmd("SADD").arg("queues".to_string()).arg(&queue_id).ignore();
+    if let Some(ref project_id) = queue_info.project_id {
+        pipe.cmd("ZADD").arg(Queue::get_project_queues_key(project_id)).arg(0).arg(&queue_name).ignore();
+    }
     pipe.cmd("HMSET").arg(&queue_key)
         .arg("name".to_string())
         .arg(&queue_name)
@@ -72,14 +192,26 @@ pub fn create_queue(queue_info: QueueInfo, con: &Connection) -> QueueInfo {
         .arg("size".to_string())
         .arg(0)
         .arg("total_messages".to_string())
-        .arg(0).ignore();
+        .arg(0)
+        .arg("history".to_string())
+        .arg(history_flag(&queue_info)).ignore();
+    // The counter orders messages, so it is never reset for an existing queue.
     queue_key.push_str(":msg:counter");
-    let _: Vec<String> = pipe.cmd("SET").arg(queue_key).arg(0).query(con).unwrap();
+    let _: Vec<String> = pipe.cmd("SETNX").arg(queue_key).arg(0).ignore().query(con).unwrap();
 
     if queue_info.clone().push.is_some() {
         let push = queue_info.clone().push.unwrap();
         if !push.error_queue.is_none() {
-            let qi = QueueInfo::new(push.error_queue.unwrap());
+            let mut qi = QueueInfo::new(push.error_queue.unwrap());
+            qi.project_id = queue_info.project_id.clone();
+            let _ = create_queue(qi, con);
+        }
+    }
+
+    if let Some(dead_letter_queue) = queue_info.dead_letter_queue.clone() {
+        if get_queue_info(queue_info.project_queue_id(&dead_letter_queue), con).is_err() {
+            let mut qi = QueueInfo::new(dead_letter_queue);
+            qi.project_id = queue_info.project_id.clone();
             let _ = create_queue(qi, con);
         }
     }
@@ -88,9 +220,13 @@ pub fn create_queue(queue_info: QueueInfo, con: &Connection) -> QueueInfo {
 }
 
 pub fn delete(queue_name: String, con: &Connection) -> Result<bool, Error> {
-    let mut match_queue_key = String::new();
-    match_queue_key.push_str("queue:");
-    match_queue_key.push_str(&queue_name);
+    if let Ok(queue_info) = get_queue_info(queue_name.clone(), con) {
+        if let (Some(project_id), Some(name)) = (queue_info.project_id, queue_info.name) {
+            let _: () = con.zrem(Queue::get_project_queues_key(&project_id), &name)?;
+        }
+    }
+
+    let mut match_queue_key = Queue::get_queue_key(&queue_name);
     match_queue_key.push_str(":*");
 
     let iter : Iter<String> = cmd("SCAN").cursor_arg(0).arg("MATCH").arg(match_queue_key).iter(con)?;
@@ -101,6 +237,7 @@ pub fn delete(queue_name: String, con: &Connection) -> Result<bool, Error> {
         let _: () = con.del(key)?;
     }
     let _: () = con.srem("queues", &queue_name)?;
+    let _: () = con.zrem(SCHEDULED_QUEUES_KEY, &queue_name)?;
     let _: () = con.del(Queue::get_queue_key(&queue_name))?;
 
     Ok(deleted)
@@ -125,6 +262,18 @@ pub fn get_queue_info(queue_name: String, con: &Connection) -> Result<QueueInfo,
         None => bail!("Queue total messages is none"),
     };
 
+    if let Some(expired_messages) = queue.expired_messages {
+        queue_info.expired_messages(expired_messages);
+    }
+
+    if let Some(compressed_bytes) = queue.compressed_bytes {
+        queue_info.compressed_bytes(compressed_bytes);
+    }
+
+    if let Some(uncompressed_bytes) = queue.uncompressed_bytes {
+        queue_info.uncompressed_bytes(uncompressed_bytes);
+    }
+
     return Ok(queue_info);
 }
 
@@ -167,15 +316,20 @@ pub fn update_subscribers(queue_name: String, mut new_subscribers: Vec<QueueSubs
     Ok(false)
 }
 
+/// Scripts read whether to keep message history from this field of the queue hash.
+fn history_flag(queue_info: &QueueInfo) -> i32 {
+    if queue_info.history.unwrap_or(false) { 1 } else { 0 }
+}
+
 pub fn update_queue_info(queue_info: QueueInfo, con: &Connection) -> Result<bool, Error> {
-    let mut queue_key = String::new();
-    queue_key.push_str("queue:");
-    queue_key.push_str(queue_info.name.clone().unwrap().as_str());
+    let queue_key = Queue::get_queue_key(&queue_info.queue_id());
 
-    let _: () = cmd("HSET")
+    let _: () = cmd("HMSET")
         .arg(queue_key)
         .arg("value")
         .arg(serde_json::to_string(&queue_info).unwrap())
+        .arg("history")
+        .arg(history_flag(&queue_info))
         .query(con)?;
 
     Ok(true)
@@ -239,6 +393,30 @@ pub fn patch_queue_info(queue_name: String, queue_info_patch: QueueInfo, con: &C
     if queue_info_patch.message_expiration.is_some() {
         current_queue_info.message_expiration = queue_info_patch.message_expiration;
     }
+    if queue_info_patch.history.is_some() {
+        current_queue_info.history = queue_info_patch.history;
+    }
+    if queue_info_patch.compression_threshold.is_some() {
+        current_queue_info.compression_threshold = queue_info_patch.compression_threshold;
+    }
+    if queue_info_patch.dedup_window.is_some() {
+        current_queue_info.dedup_window = queue_info_patch.dedup_window;
+    }
+    if queue_info_patch.max_reservations.is_some() {
+        current_queue_info.max_reservations = queue_info_patch.max_reservations;
+    }
+    if queue_info_patch.dead_letter_queue.is_some() {
+        ensure!(Queue::is_valid_name(queue_info_patch.dead_letter_queue.as_ref().unwrap()), "Queue names cannot contain ':'");
+        if current_queue_info.dead_letter_queue != queue_info_patch.dead_letter_queue {
+            let dead_letter_queue = queue_info_patch.dead_letter_queue.clone().unwrap();
+            if get_queue_info(current_queue_info.project_queue_id(&dead_letter_queue), con).is_err() {
+                let mut qi = QueueInfo::new(dead_letter_queue);
+                qi.project_id = current_queue_info.project_id.clone();
+                let _ = create_queue(qi, con);
+            }
+        }
+        current_queue_info.dead_letter_queue = queue_info_patch.dead_letter_queue;
+    }
 
     if current_queue_info.queue_type != queue_info_patch.queue_type && queue_info_patch.queue_type.is_some() {
         bail!("Queue type cannot be changed")
@@ -265,8 +443,10 @@ pub fn patch_queue_info(queue_name: String, queue_info_patch: QueueInfo, con: &C
                 new_push.retries_delay = current_push.retries_delay;
             }
             if push.error_queue.is_some() {
+                ensure!(Queue::is_valid_name(push.error_queue.as_ref().unwrap()), "Queue names cannot contain ':'");
                 if current_push.error_queue != push.error_queue {
-                    let qi = QueueInfo::new(push.error_queue.unwrap());
+                    let mut qi = QueueInfo::new(push.error_queue.unwrap());
+                    qi.project_id = current_queue_info.project_id.clone();
                     let _ = create_queue(qi, con);
                 }
             } else {
@@ -294,3 +474,51 @@ pub fn patch_queue_info(queue_name: String, queue_info_patch: QueueInfo, con: &C
         bail!("Queue failed to update");
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params(previous: Option<&str>, prefix: Option<&str>) -> ListQueuesParams {
+        ListQueuesParams {
+            per_page: QUEUES_PER_PAGE,
+            previous: previous.map(String::from),
+            prefix: prefix.map(String::from),
+            full: false
+        }
+    }
+
+    #[test]
+    fn bounds_cover_every_queue_without_previous_and_prefix() {
+        assert_eq!((b"-".to_vec(), b"+".to_vec()), list_queues_bounds(&params(None, None)));
+    }
+
+    #[test]
+    fn previous_excludes_itself() {
+        assert_eq!((b"(orders".to_vec(), b"+".to_vec()), list_queues_bounds(&params(Some("orders"), None)));
+    }
+
+    #[test]
+    fn prefix_bounds_names_starting_with_it() {
+        assert_eq!((b"[ord".to_vec(), b"[ord\xff".to_vec()), list_queues_bounds(&params(None, Some("ord"))));
+    }
+
+    #[test]
+    fn previous_within_prefix_continues_after_it() {
+        assert_eq!((b"(orders".to_vec(), b"[ord\xff".to_vec()), list_queues_bounds(&params(Some("orders"), Some("ord"))));
+        assert_eq!((b"(ord".to_vec(), b"[ord\xff".to_vec()), list_queues_bounds(&params(Some("ord"), Some("ord"))));
+    }
+
+    #[test]
+    fn previous_before_prefix_starts_at_prefix() {
+        assert_eq!((b"[ord".to_vec(), b"[ord\xff".to_vec()), list_queues_bounds(&params(Some("invoices"), Some("ord"))));
+    }
+
+    #[test]
+    fn previous_after_prefix_leaves_nothing() {
+        let (min, max) = list_queues_bounds(&params(Some("payments"), Some("ord")));
+
+        assert_eq!(b"(payments".to_vec(), min);
+        assert!(min[1..] > max[1..]);
+    }
+}
@@ -0,0 +1,181 @@
+//! Puts messages the pusher gave up on back into the push queues they came from.
+//!
+//! The pusher stores the whole `PushMessage` as the body of an error queue message,
+//! so the original message and its queue can be restored from it.
+
+use std::{
+    collections::HashMap,
+    thread,
+    time::Duration
+};
+use redis::Connection;
+use serde_json;
+use failure::Error;
+use mq::message::{find_messages, push_message, remove_messages};
+use queue::message::{
+    Message,
+    MessageFilter,
+    MessageState,
+    PushMessage,
+    FAILED_SUBSCRIBER_ATTRIBUTE,
+    FAILED_STATUS_ATTRIBUTE
+};
+use queue::queue::Queue;
+
+const REDRIVE_LIMIT: usize = 100;
+
+#[derive(Debug)]
+pub struct RedriveOptions {
+    pub project_id: String,
+    pub error_queue: String,
+    pub limit: usize,
+    pub rate: Option<u32>,
+    pub subscriber: Option<String>,
+    pub status: Option<u16>
+}
+
+impl RedriveOptions {
+    /// Parses `<project_id> <error_queue> [--limit N] [--rate N] [--subscriber NAME] [--status CODE]`.
+    /// `--rate` is the number of messages redriven per second.
+    pub fn from_args(args: &[String]) -> Result<RedriveOptions, Error> {
+        ensure!(args.len() >= 2, "Project id and error queue are required");
+
+        let mut options = RedriveOptions {
+            project_id: args[0].clone(),
+            error_queue: args[1].clone(),
+            limit: REDRIVE_LIMIT,
+            rate: None,
+            subscriber: None,
+            status: None
+        };
+
+        let mut i = 2;
+        while i < args.len() {
+            ensure!(i + 1 < args.len(), "Missing value for {}", args[i]);
+            let value = &args[i + 1];
+            match args[i].as_str() {
+                "--limit" => options.limit = value.parse()?,
+                "--rate" => {
+                    let rate: u32 = value.parse()?;
+                    ensure!(rate > 0, "Rate must be positive");
+                    options.rate = Some(rate);
+                },
+                "--subscriber" => options.subscriber = Some(value.clone()),
+                "--status" => options.status = Some(value.parse()?),
+                option => bail!("Unknown option {}", option)
+            };
+            i += 2;
+        }
+
+        Ok(options)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct RedriveReport {
+    pub redriven: usize,
+    pub skipped: usize
+}
+
+/// Pushes the original messages of up to `limit` error queue entries back to their queues
+/// and deletes the entries. Entries that cannot be restored are left in the error queue.
+pub fn redrive(options: &RedriveOptions, con: &Connection) -> Result<RedriveReport, Error> {
+    let mut attributes = HashMap::new();
+    if let Some(ref subscriber) = options.subscriber {
+        attributes.insert(FAILED_SUBSCRIBER_ATTRIBUTE.to_string(), subscriber.clone());
+    }
+    if let Some(status) = options.status {
+        attributes.insert(FAILED_STATUS_ATTRIBUTE.to_string(), status.to_string());
+    }
+    let mut filter = MessageFilter::default();
+    if !attributes.is_empty() {
+        filter.attributes = Some(attributes);
+    }
+
+    let error_queue_id = Queue::get_queue_id(&options.project_id, &options.error_queue);
+    let interval = options.rate.map(|rate| Duration::from_millis(1000 / rate as u64));
+    let mut report = RedriveReport::default();
+    for entry in find_messages(&error_queue_id, &filter, options.limit, con)? {
+        let entry_id = entry.id.clone().unwrap();
+        let failed: Option<PushMessage> = entry.payload()
+            .and_then(|payload| serde_json::from_slice(&payload).ok());
+        let (queue_name, message) = match failed {
+            Some(ref failed) if failed.queue_info.name.is_some() => {
+                (failed.queue_info.queue_id(), restore_message(&failed.msg, &entry_id))
+            },
+            _ => {
+                warn!("Error queue {:?}: message {:?} is not a failed push", options.error_queue, entry_id);
+                report.skipped += 1;
+                continue;
+            }
+        };
+
+        if let Err(e) = push_message(queue_name.clone(), message, con) {
+            warn!("Error queue {:?}: message {:?} not redriven to {:?}: {:?}", options.error_queue, entry_id, queue_name, e.to_string());
+            report.skipped += 1;
+            continue;
+        }
+        // The entry is pushed again already, so it is removed whatever state it is in.
+        remove_messages(&error_queue_id, &vec![entry_id], None, &[MessageState::Unreserved, MessageState::Reserved, MessageState::Delayed], con)?;
+        report.redriven += 1;
+
+        if let Some(interval) = interval {
+            thread::sleep(interval);
+        }
+    }
+
+    Ok(report)
+}
+
+/// Builds a new message from the one that failed. The entry id is used as dedup id,
+/// so an entry pushed again after an interrupted redrive is not delivered twice.
+fn restore_message(failed: &Message, entry_id: &String) -> Message {
+    let mut message = Message::with_body(&failed.body);
+    message.attributes = failed.attributes.clone();
+    message.content_type = failed.content_type.clone();
+    message.encoding = failed.encoding.clone();
+    message.group_id = failed.group_id.clone();
+    message.priority = failed.priority;
+    message.dedup_id = Some(format!("redrive:{}", entry_id));
+
+    message
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn options_are_parsed() {
+        let options = RedriveOptions::from_args(&args(&["p", "errors", "--limit", "10", "--rate", "5", "--subscriber", "s", "--status", "503"])).unwrap();
+
+        assert_eq!("p", options.project_id);
+        assert_eq!("errors", options.error_queue);
+        assert_eq!(10, options.limit);
+        assert_eq!(Some(5), options.rate);
+        assert_eq!(Some(String::from("s")), options.subscriber);
+        assert_eq!(Some(503), options.status);
+
+        let defaults = RedriveOptions::from_args(&args(&["p", "errors"])).unwrap();
+        assert_eq!(REDRIVE_LIMIT, defaults.limit);
+        assert!(defaults.rate.is_none());
+    }
+
+    #[test]
+    fn missing_arguments_and_values_are_rejected() {
+        assert!(RedriveOptions::from_args(&args(&["p"])).is_err());
+        assert!(RedriveOptions::from_args(&args(&["p", "errors", "--limit"])).is_err());
+        assert!(RedriveOptions::from_args(&args(&["p", "errors", "--rate", "5", "--status"])).is_err());
+    }
+
+    #[test]
+    fn unknown_options_and_invalid_values_are_rejected() {
+        assert!(RedriveOptions::from_args(&args(&["p", "errors", "--force", "1"])).is_err());
+        assert!(RedriveOptions::from_args(&args(&["p", "errors", "--rate", "0"])).is_err());
+        assert!(RedriveOptions::from_args(&args(&["p", "errors", "--limit", "many"])).is_err());
+    }
+}
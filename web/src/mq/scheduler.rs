@@ -0,0 +1,108 @@
+use std::{
+    thread,
+    time::{Duration, Instant}
+};
+use redis::{Commands, Connection, cmd};
+use failure::Error;
+use chrono::prelude::*;
+use pool::Pool;
+use mq::message::{promote_delayed_messages, requeue_expired_reservations, expire_messages, MAXIMUM_BATCH_SIZE};
+use mq::queue::SCHEDULED_QUEUES_KEY;
+
+const SCHEDULER_INTERVAL: u64 = 1;
+/// Expiry goes through every queue, so it runs far less often and on one instance at a time.
+const EXPIRATION_INTERVAL: u64 = 60;
+const EXPIRATION_LOCK_KEY: &str = "scheduler:expiration:lock";
+
+pub fn start(pool: Pool) {
+    let builder = thread::Builder::new().name("scheduler".to_string());
+    builder.spawn(move || {
+        info!("Scheduler started");
+        let mut expired_at: Option<Instant> = None;
+        loop {
+            match pool.get() {
+                Ok(connection) => {
+                    if let Err(e) = tick(&connection) {
+                        error!("Scheduler tick failed: {:?}", e.to_string());
+                    }
+
+                    let expiration_due = expired_at
+                        .map(|at| at.elapsed() >= Duration::from_secs(EXPIRATION_INTERVAL))
+                        .unwrap_or(true);
+                    if expiration_due {
+                        expired_at = Some(Instant::now());
+                        if let Err(e) = expire(&connection) {
+                            error!("Scheduler expiration failed: {:?}", e.to_string());
+                        }
+                    }
+                },
+                Err(e) => error!("Scheduler failed to get connection: {:?}", e.to_string())
+            };
+
+            thread::sleep(Duration::from_secs(SCHEDULER_INTERVAL));
+        }
+    }).expect("Scheduler thread is started");
+}
+
+/// Promotes delayed messages and requeues expired reservations of the queues that are due.
+fn tick(con: &Connection) -> Result<(), Error> {
+    let now = Utc::now().timestamp();
+    let queue_names: Vec<String> = con.zrangebyscore(SCHEDULED_QUEUES_KEY, "-inf", now)?;
+    for queue_name in queue_names {
+        match promote_delayed_messages(&queue_name, con) {
+            Ok(promoted) => {
+                if promoted > 0 {
+                    debug!("Queue {:?}: {} delayed message(s) available", queue_name, promoted);
+                }
+            },
+            Err(e) => error!("Queue {:?}: delayed messages not promoted: {:?}", queue_name, e.to_string())
+        };
+
+        match requeue_expired_reservations(&queue_name, con) {
+            Ok(requeued) => {
+                if requeued > 0 {
+                    debug!("Queue {:?}: {} expired reservation(s) returned to queue", queue_name, requeued);
+                }
+            },
+            Err(e) => error!("Queue {:?}: expired reservations not requeued: {:?}", queue_name, e.to_string())
+        };
+    }
+
+    Ok(())
+}
+
+fn expire(con: &Connection) -> Result<(), Error> {
+    let locked: Option<String> = cmd("SET")
+        .arg(EXPIRATION_LOCK_KEY)
+        .arg(1)
+        .arg("NX")
+        .arg("EX")
+        .arg(EXPIRATION_INTERVAL)
+        .query(con)?;
+    if locked.is_none() {
+        return Ok(());
+    }
+
+    let queue_names: Vec<String> = con.smembers("queues")?;
+    for queue_name in queue_names {
+        // A full batch means more messages may be due, so busy queues catch up within a run.
+        loop {
+            match expire_messages(&queue_name, con) {
+                Ok(expired) => {
+                    if expired > 0 {
+                        info!("Queue {:?}: {} message(s) expired", queue_name, expired);
+                    }
+                    if expired < MAXIMUM_BATCH_SIZE {
+                        break;
+                    }
+                },
+                Err(e) => {
+                    error!("Queue {:?}: messages not expired: {:?}", queue_name, e.to_string());
+                    break;
+                }
+            };
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,214 @@
+-- Helpers prepended to the scripts that move messages between states.
+
+-- Scores order the unreserved set. Every priority step is worth more than any
+-- counter value, so higher priorities come first and each priority stays FIFO.
+-- Priority 0 scores are the plain counter, as before priorities existed.
+local PRIORITY_STEP = 2 ^ 49
+
+-- The counter is a 64-bit integer incremented inside the script, so concurrent producers
+-- always get distinct, increasing values. Scores are doubles, exact up to 2^53, which
+-- leaves 2^49 counter values next to the priority steps. Running out is an error rather
+-- than a silent loss of order.
+-- Scores are formatted here: Lua would send large numbers in exponent form and lose precision.
+local function next_score(queue_key, msg_key)
+    local priority = tonumber(redis.call('HGET', msg_key, 'priority') or 0)
+    local counter = redis.call('INCR', queue_key .. ':msg:counter')
+    if counter >= PRIORITY_STEP then
+        error('Message counter of ' .. queue_key .. ' is exhausted')
+    end
+
+    return string.format('%.0f', counter - priority * PRIORITY_STEP)
+end
+
+
+-- Queues count the bytes of the compressed bodies they hold, before and after compression.
+-- Messages keep their sizes, so the counts follow them when they leave or change queues.
+-- sign is 1 when the message joins the queue and -1 when it leaves.
+local function count_compressed_bytes(queue_key, msg_key, sign)
+    local sizes = redis.call('HMGET', msg_key, 'compressed_size', 'uncompressed_size')
+    if sizes[1] and sizes[2] then
+        redis.call('HINCRBY', queue_key, 'compressed_bytes', sign * tonumber(sizes[1]))
+        redis.call('HINCRBY', queue_key, 'uncompressed_bytes', sign * tonumber(sizes[2]))
+    end
+end
+
+
+-- Only one message of a group can be reserved at a time. Each group has a head message,
+-- which is in the unreserved or reserved set, or in the delayed set when it was released
+-- with a delay. The group's other available messages wait in a set of their own, so
+-- reserving never walks past them. The locked groups hash maps each group to its head.
+local function get_group_key(queue_key, group_id)
+    return queue_key .. ':group:' .. group_id .. ':msg'
+end
+
+-- Makes an available message reservable, or puts it behind the head of its group.
+-- Returns true when the message is in the unreserved set.
+local function make_available(queue_key, msg_key, score)
+    local unreserved_key = queue_key .. ':unreserved:msg'
+    local group_id = redis.call('HGET', msg_key, 'group_id')
+    if not group_id then
+        redis.call('ZADD', unreserved_key, score, msg_key)
+        return true
+    end
+
+    local locked_groups_key = queue_key .. ':locked:groups'
+    local group_key = get_group_key(queue_key, group_id)
+    local head = redis.call('HGET', locked_groups_key, group_id)
+    if head and head ~= msg_key then
+        -- A message ahead of a head that is not reserved takes its place.
+        local head_score = redis.call('ZSCORE', unreserved_key, head)
+        if not head_score or tonumber(score) >= tonumber(head_score) then
+            redis.call('ZADD', group_key, score, msg_key)
+            return false
+        end
+        redis.call('ZREM', unreserved_key, head)
+        redis.call('ZADD', group_key, head_score, head)
+    end
+
+    redis.call('HSET', locked_groups_key, group_id, msg_key)
+    redis.call('ZADD', unreserved_key, score, msg_key)
+    return true
+end
+
+-- Takes a message that is leaving the queue out of its group. When it was
+-- the head, the next message of the group becomes available.
+-- Returns true when a message became available.
+local function leave_group(queue_key, msg_key)
+    local group_id = redis.call('HGET', msg_key, 'group_id')
+    if not group_id then
+        return false
+    end
+
+    local locked_groups_key = queue_key .. ':locked:groups'
+    local group_key = get_group_key(queue_key, group_id)
+    if redis.call('HGET', locked_groups_key, group_id) ~= msg_key then
+        redis.call('ZREM', group_key, msg_key)
+        return false
+    end
+
+    local next_msg = redis.call('ZRANGE', group_key, 0, 0, 'WITHSCORES')
+    if #next_msg == 0 then
+        redis.call('HDEL', locked_groups_key, group_id)
+        return false
+    end
+    redis.call('ZREM', group_key, next_msg[1])
+    redis.call('HSET', locked_groups_key, group_id, next_msg[1])
+    redis.call('ZADD', queue_key .. ':unreserved:msg', next_msg[2], next_msg[1])
+
+    return true
+end
+
+-- Messages of a group that were made available before groups had heads may still sit in the
+-- unreserved set. Returns false, after putting the message behind its head, for those.
+local function is_group_head(queue_key, msg_key, score)
+    local group_id = redis.call('HGET', msg_key, 'group_id')
+    if not group_id then
+        return true
+    end
+
+    local locked_groups_key = queue_key .. ':locked:groups'
+    local head = redis.call('HGET', locked_groups_key, group_id)
+    if not head then
+        redis.call('HSET', locked_groups_key, group_id, msg_key)
+        return true
+    end
+    if head == msg_key then
+        return true
+    end
+
+    redis.call('ZREM', queue_key .. ':unreserved:msg', msg_key)
+    redis.call('ZADD', get_group_key(queue_key, group_id), score, msg_key)
+    return false
+end
+
+-- The scheduler only visits the queues in the scheduled queues set. Their scores are the
+-- times the next delayed message is due or the next reservation expires.
+local SCHEDULED_QUEUES_KEY = 'scheduled:queues'
+
+-- The reverse of Queue::get_queue_key.
+local function get_queue_id(queue_key)
+    local project_id, name = string.match(queue_key, '^project:([^:]+):queue:(.+)$')
+    if project_id then
+        return project_id .. ':' .. name
+    end
+    return string.sub(queue_key, #'queue:' + 1)
+end
+
+-- Makes sure the scheduler visits the queue at the given time.
+local function schedule(queue_key, at)
+    local queue_id = get_queue_id(queue_key)
+    local scheduled_at = redis.call('ZSCORE', SCHEDULED_QUEUES_KEY, queue_id)
+    if not scheduled_at or tonumber(at) < tonumber(scheduled_at) then
+        redis.call('ZADD', SCHEDULED_QUEUES_KEY, at, queue_id)
+    end
+end
+
+-- Schedules the next visit from the queue's delayed and reserved messages, or takes the
+-- queue out of the scheduled queues when it has none.
+local function reschedule(queue_key)
+    local at = nil
+    for _, suffix in ipairs({':delayed:msg', ':reserved:msg'}) do
+        local first = redis.call('ZRANGE', queue_key .. suffix, 0, 0, 'WITHSCORES')
+        if #first > 0 and (not at or tonumber(first[2]) < at) then
+            at = tonumber(first[2])
+        end
+    end
+
+    if at then
+        redis.call('ZADD', SCHEDULED_QUEUES_KEY, at, get_queue_id(queue_key))
+    else
+        redis.call('ZREM', SCHEDULED_QUEUES_KEY, get_queue_id(queue_key))
+    end
+end
+
+-- Queues with history enabled keep a list of state transitions for each message.
+-- Histories outlive their messages, so a deleted or expired message can still be looked up.
+local HISTORY_TTL = 604800
+
+local function log_event(queue_key, msg_key, event, now, reservation_id)
+    if redis.call('HGET', queue_key, 'history') ~= '1' then
+        return
+    end
+
+    local id = string.sub(msg_key, #queue_key + #':msg:' + 1)
+    local history_key = queue_key .. ':history:' .. id
+    redis.call('RPUSH', history_key, cjson.encode({event = event, at = now, reservation_id = reservation_id}))
+    redis.call('EXPIRE', history_key, HISTORY_TTL)
+end
+
+-- Makes delayed messages whose delay has passed available for reservation.
+-- Returns the promoted messages, and true when one of them can be reserved.
+local function promote_due(queue_key, now, limit)
+    local delayed_key = queue_key .. ':delayed:msg'
+    local promoted = {}
+    local available = false
+    local due = redis.call('ZRANGEBYSCORE', delayed_key, '-inf', now, 'LIMIT', 0, limit)
+    for _, msg_key in ipairs(due) do
+        -- Messages released with a delay keep the position they were reserved from, and
+        -- are still the head of their group.
+        local score = redis.call('HGET', msg_key, 'score') or next_score(queue_key, msg_key)
+        redis.call('ZREM', delayed_key, msg_key)
+        available = make_available(queue_key, msg_key, score) or available
+        redis.call('HSET', msg_key, 'state', 'unreserved')
+        table.insert(promoted, redis.call('HGETALL', msg_key))
+    end
+
+    return promoted, available
+end
+
+-- Returns messages whose reservation has expired to the queue, at the position they were
+-- reserved from. Returns the number of requeued messages.
+local function requeue_expired(queue_key, now, limit)
+    local reserved_key = queue_key .. ':reserved:msg'
+    local expired = redis.call('ZRANGEBYSCORE', reserved_key, '-inf', now, 'LIMIT', 0, limit)
+    for _, msg_key in ipairs(expired) do
+        local score = redis.call('HGET', msg_key, 'score') or next_score(queue_key, msg_key)
+        redis.call('ZREM', reserved_key, msg_key)
+        log_event(queue_key, msg_key, 'timed_out', now, redis.call('HGET', msg_key, 'reservation_id') or nil)
+        make_available(queue_key, msg_key, score)
+        redis.call('HSET', msg_key, 'state', 'unreserved')
+        redis.call('HDEL', msg_key, 'reservation_id', 'reserved_at', 'reserved_until', 'consumer_id')
+    end
+
+    return #expired
+end
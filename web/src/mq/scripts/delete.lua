@@ -0,0 +1,39 @@
+-- Deletes a message. A message that has ever been reserved is only deleted with its current,
+-- unexpired reservation id, so a worker cannot delete a message reserved again by another one.
+-- KEYS[1] queue key
+-- ARGV[1] message id, ARGV[2] reservation id (may be empty), ARGV[3] now
+-- Returns 1 when deleted, 0 when the message does not exist, -1 when a reservation id is required,
+-- -2 when the reservation id is not the current one or the reservation has expired.
+local queue_key = KEYS[1]
+local reserved_key = queue_key .. ':reserved:msg'
+local msg_key = queue_key .. ':msg:' .. ARGV[1]
+local now = tonumber(ARGV[3])
+
+local msg = redis.call('HMGET', msg_key, 'state', 'reservation_id', 'reserved_count')
+if not msg[1] then
+    return 0
+end
+if tonumber(msg[3] or 0) > 0 then
+    if ARGV[2] == '' then
+        return -1
+    end
+    local reserved_until = redis.call('ZSCORE', reserved_key, msg_key)
+    if msg[2] ~= ARGV[2] or not reserved_until or tonumber(reserved_until) < now then
+        return -2
+    end
+end
+
+log_event(queue_key, msg_key, 'deleted', now, msg[2] or nil)
+local unlocked = leave_group(queue_key, msg_key)
+redis.call('ZREM', queue_key .. ':unreserved:msg', msg_key)
+redis.call('ZREM', reserved_key, msg_key)
+redis.call('ZREM', queue_key .. ':delayed:msg', msg_key)
+redis.call('ZREM', queue_key .. ':pushed:msg', msg_key)
+count_compressed_bytes(queue_key, msg_key, -1)
+redis.call('DEL', msg_key)
+redis.call('HINCRBY', queue_key, 'size', -1)
+if unlocked then
+    redis.call('PUBLISH', queue_key .. ':msg:notify', '')
+end
+
+return 1
@@ -0,0 +1,39 @@
+-- Deletes messages pushed before the given time, whatever state they are in.
+-- Also schedules the queue, so queues that had delayed or reserved messages before the
+-- scheduled queues were kept are visited by the scheduler.
+-- KEYS[1] queue key
+-- ARGV[1] pushed before, ARGV[2] maximum number of messages to expire, ARGV[3] now
+-- Returns the number of expired messages.
+local queue_key = KEYS[1]
+local pushed_key = queue_key .. ':pushed:msg'
+local pushed_before = tonumber(ARGV[1])
+local limit = tonumber(ARGV[2])
+
+local unlocked = false
+local count = 0
+local expired = redis.call('ZRANGEBYSCORE', pushed_key, '-inf', pushed_before, 'LIMIT', 0, limit)
+for _, msg_key in ipairs(expired) do
+    -- Push times filled in from message ids may outlive a message deleted meanwhile.
+    if redis.call('EXISTS', msg_key) == 1 then
+        log_event(queue_key, msg_key, 'expired', tonumber(ARGV[3]))
+        unlocked = leave_group(queue_key, msg_key) or unlocked
+        redis.call('ZREM', queue_key .. ':unreserved:msg', msg_key)
+        redis.call('ZREM', queue_key .. ':reserved:msg', msg_key)
+        redis.call('ZREM', queue_key .. ':delayed:msg', msg_key)
+        count_compressed_bytes(queue_key, msg_key, -1)
+        redis.call('DEL', msg_key)
+        count = count + 1
+    end
+    redis.call('ZREM', pushed_key, msg_key)
+end
+
+if count > 0 then
+    redis.call('HINCRBY', queue_key, 'size', -count)
+    redis.call('HINCRBY', queue_key, 'expired_messages', count)
+end
+if unlocked then
+    redis.call('PUBLISH', queue_key .. ':msg:notify', '')
+end
+reschedule(queue_key)
+
+return count
@@ -0,0 +1,72 @@
+//! Server-side scripts for message state transitions.
+//!
+//! Each transition runs as a single script, so it is atomic and takes one round-trip.
+//! Scripts are built, and hashed, once. `Script` invokes them with EVALSHA and loads the
+//! script on the first NOSCRIPT reply.
+//!
+//! Only the queue keys are passed in `KEYS`. The scripts build the keys of the queue's sets,
+//! messages, groups and histories, and of the scheduled queues, from them, and find message
+//! keys in the sets. Those keys cannot be declared up front, so Redis must run standalone:
+//! a cluster would reject keys in other slots.
+
+use redis::Script;
+
+/// Helpers for scores, message groups and history, prepended to the scripts that use them.
+const COMMON: &str = include_str!("common.lua");
+
+fn with_common(code: &str) -> Script {
+    Script::new(&[COMMON, code].concat())
+}
+
+lazy_static! {
+    static ref PUSH: Script = with_common(include_str!("push.lua"));
+    static ref PROMOTE: Script = with_common(include_str!("promote.lua"));
+    static ref RESERVE: Script = with_common(include_str!("reserve.lua"));
+    static ref TOUCH: Script = with_common(include_str!("touch.lua"));
+    static ref RELEASE: Script = with_common(include_str!("release.lua"));
+    static ref REQUEUE: Script = with_common(include_str!("requeue.lua"));
+    static ref DELETE: Script = with_common(include_str!("delete.lua"));
+    static ref EXPIRE: Script = with_common(include_str!("expire.lua"));
+    static ref TRANSFER: Script = with_common(include_str!("transfer.lua"));
+    static ref PURGE: Script = with_common(include_str!("purge.lua"));
+}
+
+pub fn push() -> &'static Script {
+    &PUSH
+}
+
+pub fn promote() -> &'static Script {
+    &PROMOTE
+}
+
+pub fn reserve() -> &'static Script {
+    &RESERVE
+}
+
+pub fn touch() -> &'static Script {
+    &TOUCH
+}
+
+pub fn release() -> &'static Script {
+    &RELEASE
+}
+
+pub fn requeue() -> &'static Script {
+    &REQUEUE
+}
+
+pub fn delete() -> &'static Script {
+    &DELETE
+}
+
+pub fn expire() -> &'static Script {
+    &EXPIRE
+}
+
+pub fn transfer() -> &'static Script {
+    &TRANSFER
+}
+
+pub fn purge() -> &'static Script {
+    &PURGE
+}
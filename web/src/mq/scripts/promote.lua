@@ -0,0 +1,14 @@
+-- Makes delayed messages whose delay has passed available for reservation.
+-- KEYS[1] queue key
+-- ARGV[1] now, ARGV[2] maximum number of messages to promote
+-- Returns the promoted messages.
+local queue_key = KEYS[1]
+
+local promoted, available = promote_due(queue_key, tonumber(ARGV[1]), tonumber(ARGV[2]))
+
+reschedule(queue_key)
+if available then
+    redis.call('PUBLISH', queue_key .. ':msg:notify', '')
+end
+
+return promoted
@@ -0,0 +1,40 @@
+-- Deletes messages in the given states, optionally only those pushed before a given time.
+-- Messages that changed state since they were chosen, e.g. got reserved, are skipped.
+-- KEYS[1] queue key
+-- ARGV[1] now, ARGV[2] pushed before ('+inf' for any time), ARGV[3] space separated states,
+-- ARGV[4..] message ids
+-- Returns the number of deleted messages.
+local queue_key = KEYS[1]
+local pushed_key = queue_key .. ':pushed:msg'
+local now = tonumber(ARGV[1])
+local pushed_before = tonumber(ARGV[2])
+local states = ' ' .. ARGV[3] .. ' '
+
+local purged = 0
+local unlocked = false
+for i = 4, #ARGV do
+    local msg_key = queue_key .. ':msg:' .. ARGV[i]
+    local pushed_at = tonumber(redis.call('ZSCORE', pushed_key, msg_key) or 0)
+    local state = redis.call('HGET', msg_key, 'state')
+
+    if state and string.find(states, ' ' .. state .. ' ', 1, true) and pushed_at < pushed_before then
+        log_event(queue_key, msg_key, 'purged', now)
+        unlocked = leave_group(queue_key, msg_key) or unlocked
+        redis.call('ZREM', queue_key .. ':unreserved:msg', msg_key)
+        redis.call('ZREM', queue_key .. ':reserved:msg', msg_key)
+        redis.call('ZREM', queue_key .. ':delayed:msg', msg_key)
+        redis.call('ZREM', pushed_key, msg_key)
+        count_compressed_bytes(queue_key, msg_key, -1)
+        redis.call('DEL', msg_key)
+        purged = purged + 1
+    end
+end
+
+if purged > 0 then
+    redis.call('HINCRBY', queue_key, 'size', -purged)
+end
+if unlocked then
+    redis.call('PUBLISH', queue_key .. ':msg:notify', '')
+end
+
+return purged
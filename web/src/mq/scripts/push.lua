@@ -0,0 +1,75 @@
+-- Stores a new message and makes it available, or schedules it when delayed.
+-- The body is stored as the raw payload.
+-- A message with a dedup id seen within the dedup window is not stored again.
+-- KEYS[1] queue key
+-- ARGV[1] message id, ARGV[2] body, ARGV[3] now, ARGV[4] delay in seconds, ARGV[5] priority,
+-- ARGV[6] group id (may be empty), ARGV[7] dedup id (may be empty), ARGV[8] dedup window in seconds,
+-- ARGV[9] attributes as JSON (may be empty), ARGV[10] body encoding (may be empty),
+-- ARGV[11] content type (may be empty), ARGV[12] body compression (may be empty),
+-- ARGV[13] body size before compression
+-- Returns the message id and 1, or the id of the original message and 0 for a duplicate.
+local queue_key = KEYS[1]
+local id = ARGV[1]
+local body = ARGV[2]
+local now = tonumber(ARGV[3])
+local delay = tonumber(ARGV[4])
+local priority = tonumber(ARGV[5])
+local group_id = ARGV[6]
+local dedup_id = ARGV[7]
+local dedup_window = tonumber(ARGV[8])
+local attributes = ARGV[9]
+local encoding = ARGV[10]
+local content_type = ARGV[11]
+local compression = ARGV[12]
+local body_size = tonumber(ARGV[13])
+local msg_key = queue_key .. ':msg:' .. id
+
+if dedup_id ~= '' then
+    local dedup_key = queue_key .. ':dedup:' .. dedup_id
+    if not redis.call('SET', dedup_key, id, 'EX', dedup_window, 'NX') then
+        return {redis.call('GET', dedup_key), 0}
+    end
+end
+
+local state = 'unreserved'
+if delay > 0 then
+    state = 'delayed'
+end
+
+redis.call('HMSET', msg_key, 'body', body, 'id', id, 'source_msg_id', id, 'state', state)
+redis.call('ZADD', queue_key .. ':pushed:msg', now, msg_key)
+if priority > 0 then
+    redis.call('HSET', msg_key, 'priority', priority)
+end
+if group_id ~= '' then
+    redis.call('HSET', msg_key, 'group_id', group_id)
+end
+if attributes ~= '' then
+    redis.call('HSET', msg_key, 'attributes', attributes)
+end
+if encoding ~= '' then
+    redis.call('HSET', msg_key, 'encoding', encoding)
+end
+if content_type ~= '' then
+    redis.call('HSET', msg_key, 'content_type', content_type)
+end
+if compression ~= '' then
+    redis.call('HMSET', msg_key, 'compression', compression, 'compressed_size', #body, 'uncompressed_size', body_size)
+    count_compressed_bytes(queue_key, msg_key, 1)
+end
+
+if delay > 0 then
+    redis.call('HSET', msg_key, 'delay', delay)
+    redis.call('ZADD', queue_key .. ':delayed:msg', now + delay, msg_key)
+    schedule(queue_key, now + delay)
+else
+    if make_available(queue_key, msg_key, next_score(queue_key, msg_key)) then
+        redis.call('PUBLISH', queue_key .. ':msg:notify', '')
+    end
+end
+
+log_event(queue_key, msg_key, 'pushed', now)
+redis.call('HINCRBY', queue_key, 'size', 1)
+redis.call('HINCRBY', queue_key, 'total_messages', 1)
+
+return {id, 1}
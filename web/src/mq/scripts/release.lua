@@ -0,0 +1,34 @@
+-- Returns a reserved message to the queue, at the position it was reserved from.
+-- With a delay the message is scheduled and becomes available once the delay has passed.
+-- KEYS[1] queue key
+-- ARGV[1] message id, ARGV[2] reservation id, ARGV[3] now, ARGV[4] delay in seconds
+-- Returns 1 when released, 0 when the reservation is not valid.
+local queue_key = KEYS[1]
+local reserved_key = queue_key .. ':reserved:msg'
+local msg_key = queue_key .. ':msg:' .. ARGV[1]
+local now = tonumber(ARGV[3])
+local delay = tonumber(ARGV[4])
+
+if redis.call('HGET', msg_key, 'reservation_id') ~= ARGV[2] then
+    return 0
+end
+if redis.call('ZREM', reserved_key, msg_key) == 0 then
+    return 0
+end
+
+redis.call('HDEL', msg_key, 'reservation_id', 'reserved_at', 'reserved_until', 'consumer_id')
+log_event(queue_key, msg_key, 'released', now, ARGV[2])
+
+if delay > 0 then
+    -- The message stays the head of its group, so the group waits for it to be promoted.
+    redis.call('ZADD', queue_key .. ':delayed:msg', now + delay, msg_key)
+    schedule(queue_key, now + delay)
+    redis.call('HSET', msg_key, 'state', 'delayed')
+else
+    local score = redis.call('HGET', msg_key, 'score') or next_score(queue_key, msg_key)
+    make_available(queue_key, msg_key, score)
+    redis.call('HSET', msg_key, 'state', 'unreserved')
+    redis.call('PUBLISH', queue_key .. ':msg:notify', '')
+end
+
+return 1
@@ -0,0 +1,14 @@
+-- Returns messages whose reservation has expired to the queue.
+-- KEYS[1] queue key
+-- ARGV[1] now, ARGV[2] maximum number of messages to requeue
+-- Returns the number of requeued messages.
+local queue_key = KEYS[1]
+
+local requeued = requeue_expired(queue_key, tonumber(ARGV[1]), tonumber(ARGV[2]))
+
+reschedule(queue_key)
+if requeued > 0 then
+    redis.call('PUBLISH', queue_key .. ':msg:notify', '')
+end
+
+return requeued
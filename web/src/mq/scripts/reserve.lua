@@ -0,0 +1,104 @@
+-- Reserves up to one message per reservation id, in queue order.
+-- Expired reservations are requeued, and due delayed messages of pull queues promoted, first.
+-- Only the head of a group is in the unreserved set, the next one follows once it is deleted.
+-- Messages reserved max_reservations times already go to the dead-letter queue instead.
+-- KEYS[1] queue key, KEYS[2] dead-letter queue key (optional)
+-- ARGV[1] now, ARGV[2] reservation expiry, ARGV[3] max reservations (0 for unlimited),
+-- ARGV[4] '1' to delete reserved messages, ARGV[5] consumer id (may be empty),
+-- ARGV[6] maximum number of messages to requeue or promote, ARGV[7] '1' to promote delayed messages,
+-- ARGV[8..] reservation ids
+-- Returns the reserved messages.
+local queue_key = KEYS[1]
+local dead_letter_key = KEYS[2]
+local unreserved_key = queue_key .. ':unreserved:msg'
+local reserved_key = queue_key .. ':reserved:msg'
+local pushed_key = queue_key .. ':pushed:msg'
+local now = tonumber(ARGV[1])
+local reserved_until = tonumber(ARGV[2])
+local max_reservations = tonumber(ARGV[3])
+local delete = ARGV[4] == '1'
+local consumer_id = ARGV[5]
+local limit = tonumber(ARGV[6])
+local promote = ARGV[7] == '1'
+
+local function dead_letter(msg_key)
+    local id = redis.call('HGET', msg_key, 'id')
+    local dead_letter_msg_key = dead_letter_key .. ':msg:' .. id
+
+    log_event(queue_key, msg_key, 'dead_lettered', now)
+    leave_group(queue_key, msg_key)
+    redis.call('ZREM', pushed_key, msg_key)
+    count_compressed_bytes(queue_key, msg_key, -1)
+    redis.call('RENAME', msg_key, dead_letter_msg_key)
+    count_compressed_bytes(dead_letter_key, dead_letter_msg_key, 1)
+    local score = next_score(dead_letter_key, dead_letter_msg_key)
+    redis.call('HSET', dead_letter_msg_key, 'state', 'unreserved')
+    redis.call('HDEL', dead_letter_msg_key, 'reservation_id', 'reserved_at', 'reserved_until', 'consumer_id', 'score')
+    make_available(dead_letter_key, dead_letter_msg_key, score)
+    redis.call('ZADD', dead_letter_key .. ':pushed:msg', now, dead_letter_msg_key)
+    redis.call('HINCRBY', queue_key, 'size', -1)
+    redis.call('HINCRBY', dead_letter_key, 'size', 1)
+    redis.call('HINCRBY', dead_letter_key, 'total_messages', 1)
+    redis.call('PUBLISH', dead_letter_key .. ':msg:notify', '')
+end
+
+local notify = false
+if promote then
+    local _, available = promote_due(queue_key, now, limit)
+    notify = available
+end
+notify = requeue_expired(queue_key, now, limit) > 0 or notify
+reschedule(queue_key)
+if notify then
+    redis.call('PUBLISH', queue_key .. ':msg:notify', '')
+end
+
+local result = {}
+local next_id = 8
+-- Every candidate leaves the unreserved set, so the next ones are always at its start.
+while next_id <= #ARGV do
+    local candidates = redis.call('ZRANGE', unreserved_key, 0, #ARGV - next_id, 'WITHSCORES')
+    if #candidates == 0 then
+        break
+    end
+
+    for i = 1, #candidates, 2 do
+        local msg_key = candidates[i]
+        local score = candidates[i + 1]
+        local reserved_count = tonumber(redis.call('HGET', msg_key, 'reserved_count') or 0)
+
+        if not is_group_head(queue_key, msg_key, score) then
+            -- Put behind its group's head, it comes back once it is the head itself.
+        elseif dead_letter_key and max_reservations > 0 and reserved_count >= max_reservations then
+            redis.call('ZREM', unreserved_key, msg_key)
+            dead_letter(msg_key)
+        else
+            redis.call('ZREM', unreserved_key, msg_key)
+            redis.call('ZADD', reserved_key, reserved_until, msg_key)
+            redis.call('HMSET', msg_key, 'reservation_id', ARGV[next_id], 'state', 'reserved', 'score', score, 'reserved_at', now, 'reserved_until', reserved_until)
+            if consumer_id ~= '' then
+                redis.call('HSET', msg_key, 'consumer_id', consumer_id)
+            else
+                redis.call('HDEL', msg_key, 'consumer_id')
+            end
+            redis.call('HINCRBY', msg_key, 'reserved_count', 1)
+            table.insert(result, redis.call('HGETALL', msg_key))
+            log_event(queue_key, msg_key, 'reserved', now, ARGV[next_id])
+            next_id = next_id + 1
+
+            if delete then
+                log_event(queue_key, msg_key, 'deleted', now)
+                leave_group(queue_key, msg_key)
+                redis.call('ZREM', reserved_key, msg_key)
+                redis.call('ZREM', pushed_key, msg_key)
+                count_compressed_bytes(queue_key, msg_key, -1)
+                redis.call('DEL', msg_key)
+                redis.call('HINCRBY', queue_key, 'size', -1)
+            else
+                schedule(queue_key, reserved_until)
+            end
+        end
+    end
+end
+
+return result
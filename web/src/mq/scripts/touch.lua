@@ -0,0 +1,26 @@
+-- Replaces the reservation id of a reserved message and extends its reservation.
+-- KEYS[1] queue key
+-- ARGV[1] message id, ARGV[2] current reservation id, ARGV[3] new reservation id, ARGV[4] reserved until,
+-- ARGV[5] now
+-- Returns the new reservation id and expiry, 0 when the message is not reserved with the
+-- reservation id, -2 when the reservation has expired, even if it was not requeued yet.
+local queue_key = KEYS[1]
+local reserved_key = queue_key .. ':reserved:msg'
+local msg_key = queue_key .. ':msg:' .. ARGV[1]
+
+local reserved_until = redis.call('ZSCORE', reserved_key, msg_key)
+if not reserved_until then
+    return 0
+end
+if redis.call('HGET', msg_key, 'reservation_id') ~= ARGV[2] then
+    return 0
+end
+if tonumber(reserved_until) < tonumber(ARGV[5]) then
+    return -2
+end
+
+redis.call('ZADD', reserved_key, ARGV[4], msg_key)
+redis.call('HMSET', msg_key, 'reservation_id', ARGV[3], 'reserved_until', ARGV[4])
+log_event(queue_key, msg_key, 'touched', tonumber(ARGV[5]), ARGV[3])
+
+return {ARGV[3], tonumber(ARGV[4])}
@@ -0,0 +1,64 @@
+-- Moves or copies messages to another queue. Reserved messages are left where they are.
+-- Messages get a new id in the destination queue and keep their source_msg_id.
+-- KEYS[1] queue key, KEYS[2] destination queue key
+-- ARGV[1] now, ARGV[2] '1' to copy instead of move, ARGV[3..] pairs of message id and new message id
+-- Returns pairs of message id and new message id for the transferred messages.
+local queue_key = KEYS[1]
+local destination_key = KEYS[2]
+local now = tonumber(ARGV[1])
+local copy = ARGV[2] == '1'
+
+local result = {}
+local available = false
+local unlocked = false
+for i = 3, #ARGV - 1, 2 do
+    local msg_key = queue_key .. ':msg:' .. ARGV[i]
+    local new_id = ARGV[i + 1]
+    local new_msg_key = destination_key .. ':msg:' .. new_id
+    local state = redis.call('HGET', msg_key, 'state')
+
+    if state and state ~= 'reserved' then
+        local pushed_at = redis.call('ZSCORE', queue_key .. ':pushed:msg', msg_key) or now
+        local due = redis.call('ZSCORE', queue_key .. ':delayed:msg', msg_key)
+
+        if copy then
+            log_event(queue_key, msg_key, 'copied', now)
+            redis.call('HMSET', new_msg_key, unpack(redis.call('HGETALL', msg_key)))
+        else
+            log_event(queue_key, msg_key, 'moved', now)
+            unlocked = leave_group(queue_key, msg_key) or unlocked
+            redis.call('ZREM', queue_key .. ':unreserved:msg', msg_key)
+            redis.call('ZREM', queue_key .. ':delayed:msg', msg_key)
+            redis.call('ZREM', queue_key .. ':pushed:msg', msg_key)
+            count_compressed_bytes(queue_key, msg_key, -1)
+            redis.call('RENAME', msg_key, new_msg_key)
+            redis.call('HINCRBY', queue_key, 'size', -1)
+        end
+
+        redis.call('HSET', new_msg_key, 'id', new_id)
+        count_compressed_bytes(destination_key, new_msg_key, 1)
+        redis.call('HDEL', new_msg_key, 'reservation_id', 'reserved_at', 'reserved_until', 'reserved_count', 'consumer_id', 'score')
+        redis.call('ZADD', destination_key .. ':pushed:msg', pushed_at, new_msg_key)
+        if due then
+            redis.call('ZADD', destination_key .. ':delayed:msg', due, new_msg_key)
+            schedule(destination_key, due)
+        else
+            available = make_available(destination_key, new_msg_key, next_score(destination_key, new_msg_key)) or available
+        end
+        redis.call('HINCRBY', destination_key, 'size', 1)
+        redis.call('HINCRBY', destination_key, 'total_messages', 1)
+        log_event(destination_key, new_msg_key, 'pushed', now)
+
+        table.insert(result, ARGV[i])
+        table.insert(result, new_id)
+    end
+end
+
+if available then
+    redis.call('PUBLISH', destination_key .. ':msg:notify', '')
+end
+if unlocked then
+    redis.call('PUBLISH', queue_key .. ':msg:notify', '')
+end
+
+return result